@@ -0,0 +1,161 @@
+// Latency and CPU benchmark for the match maker, run with `cargo bench --bench match_maker`
+//
+// Starts the server binary against the redis at REDIS_URL, then measures:
+//  - how much CPU the instance uses while nobody is queueing (the match maker and clock watcher should be asleep)
+//  - how long it takes from the second player of a pair joining a pool to both being mapped to a game
+// and exits with an error if either misses its target.
+//
+// Players are queued by writing the same keys as matchmaking::join_matchmaking, so keep the two in step.
+// It uses user ids from BENCH_USER_ID_START upwards and cleans up after itself, but point it at a scratch redis.
+
+use redis::Commands;
+use std::{
+    env, fs,
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const IDLE_SECS: u64 = 5;
+const PAIRS: u32 = 200;
+const BENCH_USER_ID_START: u32 = 4_000_000_000;
+const BENCH_POOL_SETTINGS: &str = r#"{"timeControl":{"initialSecs":10740,"incrementSecs":179},"rated":false,"variant":"standard"}"#;
+const BENCH_POOL: &str = "matchmaking_pool:standard:179+179:casual";
+const MATCH_TIMEOUT: Duration = Duration::from_secs(5);
+// USER_HZ, the unit of the times in /proc/{pid}/stat, fixed at 100 by the kernel ABI
+const CLOCK_TICKS_PER_SEC: u64 = 100;
+
+// targets
+const MAX_IDLE_CPU_PERCENT: f64 = 1.0;
+const MAX_P50_LATENCY_MS: f64 = 20.0;
+const MAX_P95_LATENCY_MS: f64 = 50.0;
+
+// kills the server however the benchmark exits
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn main() {
+    dotenv::dotenv().ok();
+    let redis_url = match env::var("REDIS_URL") {
+        Ok(redis_url) => redis_url,
+        Err(_) => {
+            println!("REDIS_URL is not set, skipping the match maker benchmark");
+            return;
+        }
+    };
+    let mut con = redis::Client::open(redis_url).expect("Invalid Redis URL")
+        .get_connection().expect("Failed to connect to Redis");
+
+    let server = Server(Command::new(env!("CARGO_BIN_EXE_radial_chess"))
+        .env("HOST_ADDR", "127.0.0.1:0")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start the server"));
+    let pid = server.0.id();
+    // let the match maker and clock watcher connect and settle
+    sleep(Duration::from_secs(1));
+
+    let cpu_before = cpu_time(pid);
+    sleep(Duration::from_secs(IDLE_SECS));
+    let idle_cpu_percent = (cpu_time(pid) - cpu_before).as_secs_f64() / IDLE_SECS as f64 * 100.0;
+
+    let cpu_before = cpu_time(pid);
+    let started = Instant::now();
+    let mut latencies_ms = Vec::new();
+    for pair in 0..PAIRS {
+        let player1 = BENCH_USER_ID_START + pair * 2;
+        let player2 = player1 + 1;
+        join(&mut con, player1);
+        let joined = Instant::now();
+        join(&mut con, player2);
+
+        while !(is_matched(&mut con, player1) && is_matched(&mut con, player2)) {
+            if joined.elapsed() > MATCH_TIMEOUT {
+                cleanup(&mut con, player1, player2);
+                panic!("users {} and {} were not matched within {:?}", player1, player2, MATCH_TIMEOUT);
+            }
+            sleep(Duration::from_micros(200));
+        }
+        latencies_ms.push(joined.elapsed().as_secs_f64() * 1000.0);
+        cleanup(&mut con, player1, player2);
+    }
+    let busy_cpu_ms_per_pair = (cpu_time(pid) - cpu_before).as_secs_f64() * 1000.0 / PAIRS as f64;
+    let busy_secs = started.elapsed().as_secs_f64();
+    let _: () = con.hdel("matchmaking_pools", BENCH_POOL).unwrap();
+    drop(server);
+
+    latencies_ms.sort_by(f64::total_cmp);
+    let percentile = |p: f64| latencies_ms[((latencies_ms.len() - 1) as f64 * p).round() as usize];
+    let (p50, p95, max) = (percentile(0.5), percentile(0.95), percentile(1.0));
+
+    println!("idle cpu:          {:.2}% of a core over {}s (target <= {}%)", idle_cpu_percent, IDLE_SECS, MAX_IDLE_CPU_PERCENT);
+    println!("pairing latency:   p50 {:.2}ms (target <= {}ms), p95 {:.2}ms (target <= {}ms), max {:.2}ms", p50, MAX_P50_LATENCY_MS, p95, MAX_P95_LATENCY_MS, max);
+    println!("cpu while pairing: {:.3}ms per pair, {} pairs in {:.2}s", busy_cpu_ms_per_pair, PAIRS, busy_secs);
+
+    let mut missed = Vec::new();
+    if idle_cpu_percent > MAX_IDLE_CPU_PERCENT {
+        missed.push("idle cpu");
+    }
+    if p50 > MAX_P50_LATENCY_MS {
+        missed.push("p50 latency");
+    }
+    if p95 > MAX_P95_LATENCY_MS {
+        missed.push("p95 latency");
+    }
+    if !missed.is_empty() {
+        eprintln!("missed targets: {}", missed.join(", "));
+        std::process::exit(1);
+    }
+}
+
+// Mirrors matchmaking::join_matchmaking
+fn join(con: &mut redis::Connection, user_id: u32) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let event = format!(r#"{{"pool_key":"{}","queued_at_ms":{}}}"#, BENCH_POOL, now.as_millis());
+    redis::pipe()
+        .set(format!("matchmaking_entry:{}", user_id), BENCH_POOL).ignore()
+        .zadd(BENCH_POOL, user_id, now.as_secs()).ignore()
+        .hset("matchmaking_pools", BENCH_POOL, BENCH_POOL_SETTINGS).ignore()
+        .lpush("matchmaking_events", event).ignore()
+        .query::<()>(con)
+        .unwrap();
+}
+
+fn is_matched(con: &mut redis::Connection, user_id: u32) -> bool {
+    con.hexists(format!("user:{}", user_id), "game_id").unwrap()
+}
+
+fn cleanup(con: &mut redis::Connection, player1: u32, player2: u32) {
+    for user_id in [player1, player2] {
+        let game_id: Option<String> = con.hget(format!("user:{}", user_id), "game_id").unwrap();
+        if let Some(game_id) = game_id {
+            let _: () = con.del(&[format!("game:{}", game_id), format!("game_events:{}", game_id)]).unwrap();
+            let _: () = con.zrem("active_games", &game_id).unwrap();
+        }
+        let _: () = con.del(&[
+            format!("user:{}", user_id),
+            format!("matchmaking_entry:{}", user_id),
+            format!("colour_history:{}", user_id),
+        ]).unwrap();
+        let _: () = con.zrem(BENCH_POOL, user_id).unwrap();
+    }
+}
+
+// Total time the process has spent on a cpu, utime + stime from /proc/{pid}/stat. These count every thread,
+// including the tokio workers running the match maker and clock watcher, where schedstat only has the main thread's
+fn cpu_time(pid: u32) -> Duration {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).expect("Failed to read the server's cpu time");
+    // the command name can contain spaces, the fields after it start at state (field 3)
+    let fields: Vec<&str> = stat.rsplit_once(')').map(|(_, fields)| fields.split_whitespace().collect()).unwrap_or_default();
+    let ticks: u64 = [fields.get(11), fields.get(12)].into_iter()
+        .map(|field| field.and_then(|ticks| ticks.parse::<u64>().ok()).expect("Unexpected stat format"))
+        .sum();
+    Duration::from_millis(ticks * 1000 / CLOCK_TICKS_PER_SEC)
+}
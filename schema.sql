@@ -0,0 +1,38 @@
+-- Finished games, archived from redis when a game ends (id is the redis game_id)
+CREATE TABLE IF NOT EXISTS games (
+    id INT UNSIGNED NOT NULL PRIMARY KEY,
+    player_white INT UNSIGNED NOT NULL,
+    player_black INT UNSIGNED NOT NULL,
+    result VARCHAR(7) NOT NULL,          -- 1-0, 0-1 or 1/2-1/2
+    termination VARCHAR(32) NOT NULL,    -- eg: checkmate, resignation, timeout
+    time_control VARCHAR(16) NOT NULL,   -- eg: 3+2
+    pgn TEXT NOT NULL,
+    final_fen VARCHAR(100) NOT NULL,
+    created_at BIGINT NOT NULL,          -- unix timestamps, as stored in redis
+    started_at BIGINT NOT NULL,
+    ended_at BIGINT NOT NULL,
+    INDEX idx_games_player_white (player_white, ended_at),
+    INDEX idx_games_player_black (player_black, ended_at)
+);
+
+CREATE TABLE IF NOT EXISTS game_moves (
+    game_id INT UNSIGNED NOT NULL,
+    ply SMALLINT UNSIGNED NOT NULL,      -- 1 for white's first move
+    san VARCHAR(10) NOT NULL,
+    uci VARCHAR(5) NOT NULL,
+    PRIMARY KEY (game_id, ply),
+    FOREIGN KEY (game_id) REFERENCES games (id) ON DELETE CASCADE
+);
+
+-- One row per player per rated game, the current rating itself lives in redis (player_stats:{user_id})
+CREATE TABLE IF NOT EXISTS rating_history (
+    id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    user_id INT UNSIGNED NOT NULL,
+    game_id INT UNSIGNED NOT NULL,
+    rating_before DOUBLE NOT NULL,
+    rating_after DOUBLE NOT NULL,
+    deviation_after DOUBLE NOT NULL,
+    volatility_after DOUBLE NOT NULL,
+    created_at BIGINT NOT NULL,
+    INDEX idx_rating_history_user (user_id, created_at)
+);
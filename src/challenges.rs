@@ -0,0 +1,276 @@
+use axum::{extract::Path, http::StatusCode, response::IntoResponse};
+use chrono::Utc;
+use http::{Method, Request};
+use log::info;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+use crate::{authlayer, gameserver::{ColourPreference, GameSettings}, matchmaking::{self, AcceptError, MatchmakingRequest, OfferedGame}, redislayer::RedisLayer, utils::cors_response};
+
+// how long a challenge stays open when the challenger doesn't choose
+const DEFAULT_CHALLENGE_EXPIRY_SECS: u64 = 600;
+const MIN_CHALLENGE_EXPIRY_SECS: u64 = 30;
+// invite links can be shared around, but shouldn't live forever
+const MAX_CHALLENGE_EXPIRY_SECS: u64 = 7 * 24 * 60 * 60;
+
+// A game offered to a specific user, or to whoever opens the invite link when there is no opponent
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Challenge {
+    pub challenge_id: String,
+    pub challenger: u32,
+    pub opponent: Option<u32>,
+    pub colour: ColourPreference, // the challenger's side
+    pub settings: GameSettings,
+    pub created: i64,
+    pub expires: i64,
+}
+
+// Body of POST /challenges, game settings are read the same way as POST /matchmaking
+#[derive(Debug, Default, Deserialize)]
+struct ChallengeRequest {
+    opponent: Option<u32>,
+    colour: Option<ColourPreference>,
+    expires_in_secs: Option<u64>,
+    #[serde(flatten)]
+    settings: MatchmakingRequest,
+}
+
+pub async fn challenge_options(req: Request<hyper::Body>) -> impl IntoResponse {
+    if req.method() == Method::OPTIONS { //respond to preflight request
+        return cors_response(StatusCode::OK, json!({"message": "Preflight request OK"}));
+    }
+    cors_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Request method is not OPTIONS!"}))
+}
+
+pub async fn create_challenge(req: Request<hyper::Body>) -> impl IntoResponse {
+    info!("POST /challenges hit!");
+
+    let user_id = match authlayer::get_jwt_sub(&req).await {
+        Ok(id) => id,
+        Err(e) => return cors_response(e.0, json!({"message": format!("encountered error: {}", e.1)})),
+    };
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return cors_response(StatusCode::BAD_REQUEST, json!({"message": format!("Failed to read request body: {}", e)})),
+    };
+
+    let challenge_request: ChallengeRequest = if body.is_empty() {
+        ChallengeRequest::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(challenge_request) => challenge_request,
+            Err(e) => return cors_response(StatusCode::BAD_REQUEST, json!({"message": format!("Invalid challenge request: {}", e)})),
+        }
+    };
+
+    if challenge_request.opponent == Some(user_id) {
+        return cors_response(StatusCode::BAD_REQUEST, json!({"message": "Users cannot challenge themselves"}));
+    }
+
+    let settings = match challenge_request.settings.into_settings() {
+        Ok(settings) => settings,
+        Err(message) => return cors_response(StatusCode::BAD_REQUEST, json!({"message": message})),
+    };
+
+    let expires_in_secs = challenge_request.expires_in_secs
+        .unwrap_or(DEFAULT_CHALLENGE_EXPIRY_SECS)
+        .clamp(MIN_CHALLENGE_EXPIRY_SECS, MAX_CHALLENGE_EXPIRY_SECS);
+    let now = Utc::now().timestamp();
+
+    let challenge = Challenge {
+        challenge_id: Uuid::new_v4().simple().to_string(), // doubles as the invite link, so it must not be guessable
+        challenger: user_id,
+        opponent: challenge_request.opponent,
+        colour: challenge_request.colour.unwrap_or_default(),
+        settings,
+        created: now,
+        expires: now + expires_in_secs as i64,
+    };
+
+    let redislayer = RedisLayer::new().await;
+
+    // redis expires the challenge itself, the per-user indexes are cleaned up lazily when listed
+    if let Err(e) = redislayer.set_ex(&challenge_key(&challenge.challenge_id), &serde_json::to_string(&challenge).unwrap(), expires_in_secs).await {
+        return cors_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": format!("encountered error saving challenge: {}", e)}));
+    }
+    let _ = redislayer.sadd(&format!("challenges_from:{}", user_id), &challenge.challenge_id).await;
+    if let Some(opponent) = challenge.opponent {
+        let _ = redislayer.sadd(&format!("challenges_to:{}", opponent), &challenge.challenge_id).await;
+        //let the opponent know if they are waiting in the websocket lobby
+        let _ = redislayer.publish(&format!("lobby:{}", opponent), &format!("challenge:new:{}", challenge.challenge_id)).await;
+    }
+
+    info!("user {} created challenge {}", user_id, challenge.challenge_id);
+
+    cors_response(StatusCode::CREATED, json!({
+        "message": "Challenge created",
+        "challenge": challenge,
+        "invite_link": format!("/challenges/{}", challenge.challenge_id),
+    }))
+}
+
+// Open challenges sent to and by the authenticated user
+pub async fn list_challenges(req: Request<hyper::Body>) -> impl IntoResponse {
+    info!("GET /challenges hit!");
+
+    let user_id = match authlayer::get_jwt_sub(&req).await {
+        Ok(id) => id,
+        Err(e) => return cors_response(e.0, json!({"message": format!("encountered error: {}", e.1)})),
+    };
+
+    let redislayer = RedisLayer::new().await;
+    let incoming = open_challenges(&format!("challenges_to:{}", user_id), &redislayer).await;
+    let outgoing = open_challenges(&format!("challenges_from:{}", user_id), &redislayer).await;
+
+    cors_response(StatusCode::OK, json!({"incoming": incoming, "outgoing": outgoing}))
+}
+
+pub async fn get_challenge(Path(challenge_id): Path<String>) -> impl IntoResponse {
+    let redislayer = RedisLayer::new().await;
+    match load_challenge(&challenge_id, &redislayer).await {
+        Some(challenge) => cors_response(StatusCode::OK, json!({"challenge": challenge})),
+        None => cors_response(StatusCode::NOT_FOUND, json!({"message": "Challenge not found or expired"})),
+    }
+}
+
+pub async fn accept_challenge(Path(challenge_id): Path<String>, req: Request<hyper::Body>) -> impl IntoResponse {
+    info!("POST /challenges/{}/accept hit!", challenge_id);
+
+    let user_id = match authlayer::get_jwt_sub(&req).await {
+        Ok(id) => id,
+        Err(e) => return cors_response(e.0, json!({"message": format!("encountered error: {}", e.1)})),
+    };
+
+    let redislayer = RedisLayer::new().await;
+
+    let challenge = match load_challenge(&challenge_id, &redislayer).await {
+        Some(challenge) => challenge,
+        None => return cors_response(StatusCode::NOT_FOUND, json!({"message": "Challenge not found or expired"})),
+    };
+
+    if challenge.challenger == user_id {
+        return cors_response(StatusCode::BAD_REQUEST, json!({"message": "Users cannot accept their own challenge"}));
+    }
+    if challenge.opponent.is_some_and(|opponent| opponent != user_id) {
+        return cors_response(StatusCode::FORBIDDEN, json!({"message": "Challenge was sent to another user"}));
+    }
+
+    // only one accept can take the challenge, anyone else finds it gone
+    let claim = async { redislayer.get_del(&challenge_key(&challenge_id)).await.map(|taken| taken.is_some()) };
+    let restore = restore_challenge(&challenge, &redislayer);
+    let close = remove_from_indexes(&challenge, &redislayer);
+    let offer = OfferedGame { offered_by: challenge.challenger, colour: challenge.colour, settings: challenge.settings };
+    match matchmaking::accept_offer(offer, user_id, claim, restore, close, &redislayer).await {
+        Ok(game_id) => {
+            let _ = redislayer.publish(&format!("lobby:{}", challenge.challenger), &format!("challenge:accepted:{}", challenge_id)).await;
+            cors_response(StatusCode::OK, json!({
+                "message": format!("Challenge accepted, created game: {}", game_id),
+                "game_id": game_id,
+                "instructions": "Open a websocket request to the server at /ws"
+            }))
+        },
+        Err(AcceptError::Taken) => cors_response(StatusCode::NOT_FOUND, json!({"message": "Challenge not found or expired"})),
+        Err(AcceptError::Conflict(message)) => cors_response(StatusCode::CONFLICT, json!({"message": message})),
+        Err(AcceptError::Failed(message)) => cors_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": message})),
+    }
+}
+
+pub async fn decline_challenge(Path(challenge_id): Path<String>, req: Request<hyper::Body>) -> impl IntoResponse {
+    info!("POST /challenges/{}/decline hit!", challenge_id);
+
+    let user_id = match authlayer::get_jwt_sub(&req).await {
+        Ok(id) => id,
+        Err(e) => return cors_response(e.0, json!({"message": format!("encountered error: {}", e.1)})),
+    };
+
+    let redislayer = RedisLayer::new().await;
+
+    let challenge = match load_challenge(&challenge_id, &redislayer).await {
+        Some(challenge) => challenge,
+        None => return cors_response(StatusCode::NOT_FOUND, json!({"message": "Challenge not found or expired"})),
+    };
+
+    // an invite link has nobody to decline it, the challenger cancels it instead
+    if challenge.opponent != Some(user_id) {
+        return cors_response(StatusCode::FORBIDDEN, json!({"message": "Only the challenged user can decline a challenge"}));
+    }
+
+    close_challenge(&challenge, &redislayer).await;
+    let _ = redislayer.publish(&format!("lobby:{}", challenge.challenger), &format!("challenge:declined:{}", challenge_id)).await;
+
+    cors_response(StatusCode::OK, json!({"message": "Challenge declined"}))
+}
+
+pub async fn cancel_challenge(Path(challenge_id): Path<String>, req: Request<hyper::Body>) -> impl IntoResponse {
+    info!("DELETE /challenges/{} hit!", challenge_id);
+
+    let user_id = match authlayer::get_jwt_sub(&req).await {
+        Ok(id) => id,
+        Err(e) => return cors_response(e.0, json!({"message": format!("encountered error: {}", e.1)})),
+    };
+
+    let redislayer = RedisLayer::new().await;
+
+    let challenge = match load_challenge(&challenge_id, &redislayer).await {
+        Some(challenge) => challenge,
+        None => return cors_response(StatusCode::NOT_FOUND, json!({"message": "Challenge not found or expired"})),
+    };
+
+    if challenge.challenger != user_id {
+        return cors_response(StatusCode::FORBIDDEN, json!({"message": "Only the challenger can cancel a challenge"}));
+    }
+
+    close_challenge(&challenge, &redislayer).await;
+    if let Some(opponent) = challenge.opponent {
+        let _ = redislayer.publish(&format!("lobby:{}", opponent), &format!("challenge:cancelled:{}", challenge_id)).await;
+    }
+
+    cors_response(StatusCode::OK, json!({"message": "Challenge cancelled"}))
+}
+
+pub async fn load_challenge(challenge_id: &str, redislayer: &RedisLayer) -> Option<Challenge> {
+    let challenge = redislayer.get(&challenge_key(challenge_id)).await.ok()??;
+    serde_json::from_str(&challenge).ok()
+}
+
+async fn open_challenges(index_key: &str, redislayer: &RedisLayer) -> Vec<Challenge> {
+    let mut challenges = Vec::new();
+    for challenge_id in redislayer.smembers(index_key).await.unwrap_or_default() {
+        match load_challenge(&challenge_id, redislayer).await {
+            Some(challenge) => challenges.push(challenge),
+            None => { let _ = redislayer.srem(index_key, &challenge_id).await; }, // expired
+        }
+    }
+    challenges.sort_by_key(|challenge| challenge.created);
+    challenges
+}
+
+async fn close_challenge(challenge: &Challenge, redislayer: &RedisLayer) {
+    let _ = redislayer.del(&challenge_key(&challenge.challenge_id)).await;
+    remove_from_indexes(challenge, redislayer).await;
+}
+
+// Puts back a challenge taken by an accept which couldn't go ahead, so it can still be accepted until it expires
+async fn restore_challenge(challenge: &Challenge, redislayer: &RedisLayer) {
+    let remaining_secs = challenge.expires - Utc::now().timestamp();
+    if remaining_secs <= 0 {
+        return;
+    }
+    if let Err(e) = redislayer.set_ex(&challenge_key(&challenge.challenge_id), &serde_json::to_string(challenge).unwrap(), remaining_secs as u64).await {
+        info!("Failed to restore challenge {}: {}", challenge.challenge_id, e);
+    }
+}
+
+async fn remove_from_indexes(challenge: &Challenge, redislayer: &RedisLayer) {
+    let _ = redislayer.srem(&format!("challenges_from:{}", challenge.challenger), &challenge.challenge_id).await;
+    if let Some(opponent) = challenge.opponent {
+        let _ = redislayer.srem(&format!("challenges_to:{}", opponent), &challenge.challenge_id).await;
+    }
+}
+
+fn challenge_key(challenge_id: &str) -> String {
+    format!("challenge:{}", challenge_id)
+}
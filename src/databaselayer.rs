@@ -12,6 +12,8 @@ use redis::AsyncCommands;
 use chrono::{Utc};
 use log::info;
 use dotenv::dotenv;
+use crate::gameserver::Game;
+use crate::rating::Rating;
 
 pub fn connect_to_db() -> Result<PooledConn, Box<dyn std::error::Error>> {
     let url = env::var("DATABASE_URL")?;
@@ -42,3 +44,97 @@ pub fn create_user(conn: &mut PooledConn, external_user_id: &str) -> Result<u32,
         None => Err("Failed to retrieve newly created user".into()),
     }
 }
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GameRecord {
+    pub game_id: u32,
+    pub player_white: u32,
+    pub player_black: u32,
+    pub result: String,
+    pub termination: String,
+    pub time_control: String,
+    pub pgn: String,
+    pub final_fen: String,
+    pub created_at: i64,
+    pub started_at: i64,
+    pub ended_at: i64,
+}
+
+const GAME_RECORD_COLUMNS: &str = "id, player_white, player_black, result, termination, time_control, pgn, final_fen, created_at, started_at, ended_at";
+
+// Writes a finished game and its moves in a single transaction
+pub fn save_finished_game(conn: &mut PooledConn, game: &Game, pgn: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let result = serde_json::to_value(game.result.ok_or("Game has not finished")?)?;
+    let termination = serde_json::to_value(game.termination.ok_or("Game has no termination reason")?)?;
+
+    let mut tx = conn.start_transaction(TxOpts::default())?;
+
+    tx.exec_drop(
+        r"INSERT INTO games (id, player_white, player_black, result, termination, time_control, pgn, final_fen, created_at, started_at, ended_at)
+          VALUES (:id, :player_white, :player_black, :result, :termination, :time_control, :pgn, :final_fen, :created_at, :started_at, :ended_at)",
+        params! {
+            "id" => game.game_id,
+            "player_white" => game.player_white,
+            "player_black" => game.player_black,
+            "result" => result.as_str().unwrap_or_default(),
+            "termination" => termination.as_str().unwrap_or_default(),
+            "time_control" => game.time_control.to_string(),
+            "pgn" => pgn,
+            "final_fen" => &game.board_state,
+            "created_at" => game.game_created,
+            "started_at" => game.game_initiated,
+            "ended_at" => game.game_ended,
+        },
+    )?;
+
+    tx.exec_batch(
+        r"INSERT INTO game_moves (game_id, ply, san, uci) VALUES (:game_id, :ply, :san, :uci)",
+        game.moves.iter().enumerate().map(|(index, played_move)| params! {
+            "game_id" => game.game_id,
+            "ply" => index + 1,
+            "san" => &played_move.san,
+            "uci" => &played_move.uci,
+        }),
+    )?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn save_rating_history(conn: &mut PooledConn, user_id: u32, game_id: u32, before: &Rating, after: &Rating, timestamp: i64) -> Result<(), Box<dyn std::error::Error>> {
+    conn.exec_drop(
+        r"INSERT INTO rating_history (user_id, game_id, rating_before, rating_after, deviation_after, volatility_after, created_at)
+          VALUES (:user_id, :game_id, :rating_before, :rating_after, :deviation_after, :volatility_after, :created_at)",
+        params! {
+            "user_id" => user_id,
+            "game_id" => game_id,
+            "rating_before" => before.rating,
+            "rating_after" => after.rating,
+            "deviation_after" => after.deviation,
+            "volatility_after" => after.volatility,
+            "created_at" => timestamp,
+        },
+    )?;
+    Ok(())
+}
+
+pub fn get_game_record(conn: &mut PooledConn, game_id: u32) -> Result<Option<GameRecord>, Box<dyn std::error::Error>> {
+    let query = format!("SELECT {} FROM games WHERE id = ?", GAME_RECORD_COLUMNS);
+    let row: Option<Row> = conn.exec_first(query, (game_id,))?;
+    Ok(row.map(game_record_from_row))
+}
+
+// A user's finished games, most recent first
+pub fn get_games_for_user(conn: &mut PooledConn, user_id: u32, limit: u32, offset: u32) -> Result<Vec<GameRecord>, Box<dyn std::error::Error>> {
+    let query = format!(
+        "SELECT {} FROM games WHERE player_white = :user_id OR player_black = :user_id ORDER BY ended_at DESC LIMIT :limit OFFSET :offset",
+        GAME_RECORD_COLUMNS
+    );
+    let rows: Vec<Row> = conn.exec(query, params! {"user_id" => user_id, "limit" => limit, "offset" => offset})?;
+    Ok(rows.into_iter().map(game_record_from_row).collect())
+}
+
+fn game_record_from_row(row: Row) -> GameRecord {
+    let (game_id, player_white, player_black, result, termination, time_control, pgn, final_fen, created_at, started_at, ended_at) = from_row(row);
+    GameRecord { game_id, player_white, player_black, result, termination, time_control, pgn, final_fen, created_at, started_at, ended_at }
+}
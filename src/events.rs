@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use crate::{
+    gameserver::{Clock, GameResult, Move, Termination},
+    rating::RatingChange,
+};
+
+// Internal events appended to the game_events:{game_id} stream, every message_sender reading the game relays them to its client.
+// New variants can be added freely, older readers see them as Unknown and skip them.
+// Events carry whatever state they are shown with, as they may be relayed long after the game has moved on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", rename_all_fields = "camelCase")]
+pub enum GameEvent {
+    MoveMade {
+        user_id: u32,
+        this_move: Move,
+        clock: Clock,
+    },
+    Surrendered {
+        user_id: u32,
+        #[serde(default)]
+        result: Option<GameResult>,
+        #[serde(default)]
+        termination: Option<Termination>,
+    },
+    Disconnected {
+        user_id: u32,
+        #[serde(default)]
+        clock: Option<Clock>,
+    },
+    Reconnected {
+        user_id: u32,
+        #[serde(default)]
+        clock: Option<Clock>,
+    },
+    DrawOffered {
+        user_id: u32,
+    },
+    DrawAccepted {
+        user_id: u32,
+    },
+    DrawDeclined {
+        user_id: u32,
+    },
+    GameOver {
+        #[serde(default)]
+        result: Option<GameResult>,
+        #[serde(default)]
+        termination: Option<Termination>,
+        #[serde(default)]
+        rating_change_white: Option<RatingChange>,
+        #[serde(default)]
+        rating_change_black: Option<RatingChange>,
+    },
+    // the game was called off before it got going
+    GameAborted,
+    // closes every client's connection to the game
+    GameClosed,
+    RematchOffered {
+        user_id: u32,
+        #[serde(default)]
+        result: Option<GameResult>,
+        #[serde(default)]
+        termination: Option<Termination>,
+    },
+    RematchAccepted {
+        game_id: u32,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+// An event read back from the stream, seq counts up from 1 per game and is also the entry's id ({seq}-0),
+// so a reader can pick up from the last seq it saw
+#[derive(Debug, Clone)]
+pub struct GameUpdate {
+    pub seq: u64,
+    pub event: Result<GameEvent, String>, // the error for an entry which could not be parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_written_before_events_carried_state_still_parse() {
+        match serde_json::from_str(r#"{"type":"game_over"}"#).unwrap() {
+            GameEvent::GameOver { result: None, termination: None, rating_change_white: None, rating_change_black: None } => (),
+            event => panic!("unexpected {:?}", event),
+        }
+        match serde_json::from_str(r#"{"type":"surrendered","userId":3}"#).unwrap() {
+            GameEvent::Surrendered { user_id: 3, result: None, termination: None } => (),
+            event => panic!("unexpected {:?}", event),
+        }
+        assert!(matches!(serde_json::from_str(r#"{"type":"disconnected","userId":3}"#).unwrap(), GameEvent::Disconnected { user_id: 3, clock: None }));
+        assert!(matches!(serde_json::from_str(r#"{"type":"something_newer","userId":3}"#).unwrap(), GameEvent::Unknown));
+    }
+
+    #[test]
+    fn game_over_round_trips_with_its_outcome() {
+        let json = serde_json::to_string(&GameEvent::GameOver {
+            result: Some(GameResult::BlackWins),
+            termination: Some(Termination::Timeout),
+            rating_change_white: None,
+            rating_change_black: None,
+        }).unwrap();
+        match serde_json::from_str(&json).unwrap() {
+            GameEvent::GameOver { result: Some(GameResult::BlackWins), termination: Some(Termination::Timeout), .. } => (),
+            event => panic!("unexpected {:?} from {}", event, json),
+        }
+    }
+}
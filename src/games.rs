@@ -0,0 +1,75 @@
+use axum::{extract::{Path, Query}, http::StatusCode, response::IntoResponse};
+use http::{header, Method, Request, Response};
+use hyper::Body;
+use log::info;
+use serde::Deserialize;
+use serde_json::json;
+use crate::{authlayer, databaselayer, pgn, redislayer::RedisLayer, utils::cors_response};
+
+const DEFAULT_HISTORY_LIMIT: u32 = 20;
+const MAX_HISTORY_LIMIT: u32 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryParams {
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+pub async fn game_pgn(Path(game_id): Path<u32>) -> impl IntoResponse {
+    info!("GET /games/{}/pgn hit!", game_id);
+
+    let redislayer = RedisLayer::new().await;
+
+    // games still in redis are exported live, finished games which have been archived come from the database
+    let pgn = match redislayer.get_game(game_id).await {
+        Some(game) => pgn::format_pgn(&game),
+        None => {
+            let mut conn = match databaselayer::connect_to_db() {
+                Ok(conn) => conn,
+                Err(_) => return cors_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Database connection failed"})),
+            };
+            match databaselayer::get_game_record(&mut conn, game_id) {
+                Ok(Some(record)) => record.pgn,
+                Ok(None) => return cors_response(StatusCode::NOT_FOUND, json!({"message": format!("No game found with id: {}", game_id)})),
+                Err(e) => return cors_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": format!("encountered error loading game: {}", e)})),
+            }
+        }
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(header::ACCESS_CONTROL_ALLOW_HEADERS, "*")
+        .header(header::ACCESS_CONTROL_ALLOW_METHODS, "GET, OPTIONS")
+        .header(header::CONTENT_TYPE, "application/x-chess-pgn")
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"game_{}.pgn\"", game_id))
+        .body(Body::from(pgn))
+        .unwrap()
+}
+
+// The authenticated user's finished games, most recent first
+pub async fn past_games(Query(params): Query<HistoryParams>, req: Request<hyper::Body>) -> impl IntoResponse {
+    if req.method() == Method::OPTIONS { //respond to preflight request
+        return cors_response(StatusCode::OK, json!({"message": "Preflight request OK"}));
+    }
+
+    info!("GET /games hit!");
+
+    let user_id = match authlayer::get_jwt_sub(&req).await {
+        Ok(id) => id,
+        Err(e) => return cors_response(e.0, json!({"message": format!("encountered error: {}", e.1)})),
+    };
+
+    let limit = params.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).min(MAX_HISTORY_LIMIT);
+    let offset = params.offset.unwrap_or(0);
+
+    let mut conn = match databaselayer::connect_to_db() {
+        Ok(conn) => conn,
+        Err(_) => return cors_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Database connection failed"})),
+    };
+
+    match databaselayer::get_games_for_user(&mut conn, user_id, limit, offset) {
+        Ok(games) => cors_response(StatusCode::OK, json!({"games": games, "limit": limit, "offset": offset})),
+        Err(e) => cors_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": format!("encountered error loading games: {}", e)})),
+    }
+}
@@ -1,37 +1,48 @@
-use std::{env, sync::Arc};
+use std::{env, sync::Arc, time::Duration};
 use axum::{
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
     response::IntoResponse, Json,
 };
 use chrono::Utc;
-use redis_async::{client::pubsub, resp::FromResp};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use tokio::{sync::Mutex, task};
-use crate::{authlayer, redislayer::RedisLayer, utils::decode_user_id};
+use crate::{authlayer, databaselayer, events::GameEvent, matchmaking, notation, pgn, protocol::{ClientMessage, MoveRequest, ServerMessage}, rating::{Rating, RatingChange}, redislayer::RedisLayer, utils::decode_user_id};
 use crate::utils::user_id_to_game_id;
-use futures::{stream::{SplitSink, SplitStream}, SinkExt, StreamExt};
+use futures::{stream::{SplitSink, SplitStream}, SinkExt};
 use log::info;
 use redis::{PubSub, ToRedisArgs};
 // extern crate pleco;
 use pleco;
-use pleco::{core::piece_move::{MoveFlag, PreMoveInfo}, BitMove, Board, PieceType, SQ};
+use pleco::{BitBoard, BitMove, Board, PieceType, Player};
 use dotenv::dotenv;
 use tokio::sync::mpsc::{self, Sender};
 
+const CLOCK_WATCHER_INTERVAL: Duration = Duration::from_millis(250);
+// game ids scored by the time (ms) the side to move's flag falls, so the clock watcher only reads games which are out of time
+const CLOCK_DEADLINES: &str = "clock_deadlines";
+// how long after a game ends players can offer or accept a rematch, sockets are kept open until then
+const REMATCH_GRACE_SECS: i64 = 30;
+// how long a finished or aborted game's event stream is kept, long enough to outlast the rematch window
+const GAME_EVENTS_EXPIRY_SECS: i64 = REMATCH_GRACE_SECS * 2;
+// longest message_sender waits on the game's event stream before checking in again
+const GAME_EVENTS_BLOCK_MS: u64 = 5000;
+
 // A game server to handle the game state when connecting over WebSocket to a single user
 pub struct GameServer {
     redis_layer: Arc<Mutex<RedisLayer>>,
+    sender: Arc<Mutex<SplitSink<WebSocket, Message>>>, // for replies meant only for this user, everything else is relayed from the game's event stream by message_sender
     game_id: u32,
     user_id: u32,
 }
 
 impl GameServer {
-    pub async fn new(game_id: u32, user_id: u32) -> Self {
+    pub async fn new(game_id: u32, user_id: u32, sender: Arc<Mutex<SplitSink<WebSocket, Message>>>) -> Self {
         let redis_layer = RedisLayer::new().await;
 
         GameServer {
             redis_layer: Arc::new(Mutex::new(redis_layer)),
+            sender: sender,
             game_id: game_id,
             user_id: user_id,
         }
@@ -39,26 +50,38 @@ impl GameServer {
 
     pub async fn handle_received_message(&self, msg: String) {
         info!("message from client {}", msg);
-        let parsed_message: EventMessage = match serde_json::from_str(&msg) {
+        let parsed_message: ClientMessage = match serde_json::from_str(&msg) {
             Ok(m) => m,
             Err(e) => {
                 info!("Failed to parse JSON: {}", e);
+                // a move the server can't read still needs rolling back on the client
+                let event = serde_json::from_str::<serde_json::Value>(&msg).ok()
+                    .and_then(|value| value.get("event").and_then(|event| event.as_str()).map(str::to_string));
+                if event.as_deref() == Some("game_move") {
+                    self.reject_move(None, MoveRejection::MalformedPayload).await;
+                }
                 return;
             },
         };
     
-        match parsed_message.event.as_str() {
-            "game_move" => self.handle_move(parsed_message.data).await,
-            "game_surrender" => self.handle_surrender().await,
-            "game_offer_draw" => handle_offer_draw(),
-            "game_accept_draw" => handle_accept_draw(),
-            "game_decline_draw" => handle_decline_draw(),
-            "game_reminder" => handle_send_reminder(),
-            _ => (),
+        match parsed_message {
+            ClientMessage::Ping => self.reply(ServerMessage::Pong).await,
+            ClientMessage::GameMove(data) => self.handle_move(data).await,
+            ClientMessage::GameSurrender => self.handle_surrender().await,
+            ClientMessage::GameOfferDraw => self.handle_offer_draw().await,
+            ClientMessage::GameAcceptDraw => self.handle_accept_draw().await,
+            ClientMessage::GameDeclineDraw => self.handle_decline_draw().await,
+            ClientMessage::GameClaimDraw => self.handle_claim_draw().await,
+            ClientMessage::GameOfferRematch => self.handle_offer_rematch().await,
+            ClientMessage::GameAcceptRematch => self.handle_accept_rematch().await,
+            ClientMessage::GameReminder => self.handle_send_reminder().await,
+            ClientMessage::Authenticate { .. } | ClientMessage::MatchmakingJoin(_) | ClientMessage::MatchmakingLeave => {
+                info!("Ignoring message from user {} which is not valid during a game", self.user_id);
+            },
         }
     }
 
-    async fn handle_move(&self, data: EventData) {
+    async fn handle_move(&self, data: MoveRequest) {
         info!("hit game move!");
         let redis_layer = self.redis_layer.lock().await;
         let game = match redis_layer.get_game(self.game_id).await {
@@ -69,137 +92,768 @@ impl GameServer {
             },
         };
     
+        if game.is_over() {
+            info!("Invalid! Game {} has already finished", game.game_id);
+            self.reject_move(Some(game), MoveRejection::GameOver).await;
+            return;
+        }
+
         if game.last_moved.0 == self.user_id {
             info!("Invalid! Player has already taken their turn");
+            self.reject_move(Some(game), MoveRejection::NotYourTurn).await;
             return;
         }
     
         let mut board: Board = Board::from_fen(&game.board_state).expect("Failed to load board state");
+
+        let now_ms = Utc::now().timestamp_millis();
+        let mover = board.turn();
+        let mut clock = game.clock;
+
+        if let Some((result, termination)) = flag_fall(&clock, &board, now_ms) {
+            info!("Invalid! Player {} has run out of time", self.user_id);
+            finish_game(&redis_layer, &game, result, termination).await;
+            // re-read so the client is sent the result the flag fall has just set
+            drop(redis_layer);
+            self.reject_move(None, MoveRejection::GameOver).await;
+            return;
+        }
     
-        let data = Arc::new(data);
-    
-        let bit_move = match construct_bit_move(data.clone(), &board) {
+        let bit_move = match construct_bit_move(&data, &board) {
             Ok(bmove) => bmove,
-            Err(e) => {
-                info!("{}",e.as_str());
+            Err(rejection) => {
+                info!("Invalid! Rejected move from player {}: {:?}", self.user_id, rejection);
+                self.reject_move(Some(game), rejection).await;
                 return;
             },
         };
     
-        board.apply_move(bit_move);
+        let mut moves = game.moves.clone();
+        moves.push(PlayedMove {
+            san: notation::move_to_san(&board, bit_move),
+            uci: notation::move_to_uci(bit_move),
+        });
 
-        let this_move = data.this_move.clone().expect("Failed to unwrap Move from Option!");
+        let previous_move = match &data.this_move {
+            Some(this_move) => Move {
+                from: bit_move.get_src().to_string(),
+                to: king_destination(bit_move),
+                flags: this_move.flags.clone(),
+                captured: if this_move.captured.is_some() {this_move.captured.clone()} else {None},
+                promotion: if this_move.promotion.is_some() {this_move.promotion.clone()} else {None},
+            },
+            // moves sent as UCI or SAN are relayed in the chess.js shape the frontend expects
+            None => chess_js_move(&board, bit_move),
+        };
+
+        board.apply_move(bit_move);
 
         info!("applied move!");
-        let previous_move = Move {
-            from: bit_move.get_src().to_string(),
-            to: bit_move.get_dest().to_string(),
-            flags: this_move.flags.clone(),
-            captured: if this_move.captured.is_some() {this_move.captured.clone()} else {None},
-            promotion: if this_move.promotion.is_some() {this_move.promotion.clone()} else {None},
-        };
 
-        let fields = vec![
+        let mut position_history = game.position_history.clone();
+        position_history.push(board.zobrist());
+
+        clock.press(mover, now_ms, &game.time_control);
+
+        let mut fields = vec![
             ("board_state".to_string(), board.fen()),
+            ("position_history".to_string(), serde_json::to_string(&position_history).unwrap()),
+            ("moves".to_string(), serde_json::to_string(&moves).unwrap()),
             ("last_moved".to_string(), serde_json::to_string(&(self.user_id, Utc::now().timestamp())).unwrap()),
             ("previous_move".to_string(), serde_json::to_string(&previous_move).unwrap()),
+            ("clock".to_string(), serde_json::to_string(&clock).unwrap()),
         ];
 
-        if let Err(e) = redis_layer.hset_multiple(&format!("game:{}", game.game_id), &fields).await {
-            info!("Error setting game info: {}", e);
-            return;
+        // a pending draw offer expires once the offering player makes their move
+        if game.draw_offer == Some(self.user_id) {
+            fields.push(("draw_offer".to_string(), serde_json::to_string(&None::<u32>).unwrap()));
+        }
+
+        match redis_layer.apply_move(game.game_id, game.ply, &fields).await {
+            Ok(true) => (),
+            Ok(false) => {
+                // eg: the same move sent twice from a double click or a second tab, or the game finishing at the same time
+                info!("Rejected move from player {}, game {} moved on from ply {} or has finished", self.user_id, game.game_id, game.ply);
+                let game = redis_layer.get_game(game.game_id).await;
+                drop(redis_layer);
+                let rejection = if game.as_ref().is_some_and(Game::is_over) {MoveRejection::GameOver} else {MoveRejection::StalePosition};
+                self.reject_move(game, rejection).await;
+                return;
+            },
+            Err(e) => {
+                info!("Error setting game info: {}", e);
+                return;
+            },
         }
+        track_clock_deadline(&redis_layer, game.game_id, &clock, board.turn()).await;
         info!("publishing move!");
-        let _ = redis_layer.publish(&format!("game_updates:{}", game.game_id), &format!("move:new:{}", self.user_id)).await;
-    
+        let _ = redis_layer.publish_game_event(game.game_id, GameEvent::MoveMade { user_id: self.user_id, this_move: previous_move, clock }).await;
+
+        if let Some((result, termination)) = board_outcome(&board, &position_history) {
+            info!("game {} finished by {:?}", game.game_id, termination);
+            finish_game(&redis_layer, &game, result, termination).await;
+        }
     }
 
     async fn handle_surrender(&self) {
-        let redis_layer = RedisLayer::new().await;
+        let redis_layer = self.redis_layer.lock().await;
         let game = redis_layer.get_game(self.game_id).await.expect("failed to get game");
 
-        let rem_result = redis_layer.zrem("active_games", &self.game_id.to_string()).await;
-        match rem_result {
-            Ok(_) => info!("removed game from active game pool!"),
-            Err(e) => info!("Failed to remove game from active games!, {}", e)
+        if game.is_over() {
+            info!("Invalid! Game {} has already finished", game.game_id);
+            return;
         }
-    
-        let _ = redis_layer.del(&format!("user:{}",self.user_id)).await;
-        let _ = redis_layer.del(&format!("user:{}",if game.player_black == self.user_id {game.player_white} else {game.player_black})).await;
 
-        let _ = redis_layer.publish(&format!("game_updates:{}", game.game_id), &format!("player:surrender:{}", self.user_id)).await;
-        //zrem game from "active_games" X
-        //remove user -> game mapping X
-        //publish player:surrender:{user_id} on game_updates:{game_id} X
-        //close connection (client will need to close connection once they have received the game finish / surrender message) TODO
+        let result = if game.player_white == self.user_id {GameResult::BlackWins} else {GameResult::WhiteWins};
+        if !claim_game(&redis_layer, game.game_id).await {
+            return;
+        }
+        let surrendered = GameEvent::Surrendered { user_id: self.user_id, result: Some(result), termination: Some(Termination::Resignation) };
+        let _ = redis_layer.publish_game_event(game.game_id, surrendered).await;
+
+        settle_game(&redis_layer, &game, result, Termination::Resignation).await;
+    }
+
+    async fn handle_offer_draw(&self) {
+        let redis_layer = self.redis_layer.lock().await;
+        let game = match redis_layer.get_game(self.game_id).await {
+            Some(game) => game,
+            None => {
+                info!("Failed to retreive game (handle_offer_draw)");
+                return;
+            },
+        };
+        let opponent_id = if game.player_black == self.user_id {game.player_white} else {game.player_black};
+
+        if game.is_over() {
+            info!("Invalid! Game {} has already finished", game.game_id);
+            return;
+        }
+
+        match game.draw_offer {
+            Some(offered_by) if offered_by == self.user_id => {
+                info!("Player {} has already offered a draw", self.user_id);
+                return;
+            },
+            Some(offered_by) if offered_by == opponent_id => {
+                // both players want a draw, so treat the offer as an acceptance
+                drop(redis_layer);
+                self.handle_accept_draw().await;
+                return;
+            },
+            _ => (),
+        }
+
+        if let Err(e) = redis_layer.hset(&format!("game:{}", game.game_id), "draw_offer", &serde_json::to_string(&Some(self.user_id)).unwrap()).await {
+            info!("Error storing draw offer: {}", e);
+            return;
+        }
+        let _ = redis_layer.publish_game_event(game.game_id, GameEvent::DrawOffered { user_id: self.user_id }).await;
+    }
+
+    async fn handle_accept_draw(&self) {
+        let redis_layer = self.redis_layer.lock().await;
+        let game = match redis_layer.get_game(self.game_id).await {
+            Some(game) => game,
+            None => {
+                info!("Failed to retreive game (handle_accept_draw)");
+                return;
+            },
+        };
+        let opponent_id = if game.player_black == self.user_id {game.player_white} else {game.player_black};
+
+        if game.is_over() || game.draw_offer != Some(opponent_id) {
+            info!("Invalid! No draw offer from the opponent to accept");
+            return;
+        }
+
+        if !claim_game(&redis_layer, game.game_id).await {
+            return;
+        }
+        let _ = redis_layer.hset(&format!("game:{}", game.game_id), "draw_offer", &serde_json::to_string(&None::<u32>).unwrap()).await;
+        let _ = redis_layer.publish_game_event(game.game_id, GameEvent::DrawAccepted { user_id: self.user_id }).await;
+
+        settle_game(&redis_layer, &game, GameResult::Draw, Termination::Agreement).await;
+    }
+
+    async fn handle_decline_draw(&self) {
+        let redis_layer = self.redis_layer.lock().await;
+        let game = match redis_layer.get_game(self.game_id).await {
+            Some(game) => game,
+            None => {
+                info!("Failed to retreive game (handle_decline_draw)");
+                return;
+            },
+        };
+        let opponent_id = if game.player_black == self.user_id {game.player_white} else {game.player_black};
+
+        if game.draw_offer != Some(opponent_id) {
+            info!("Invalid! No draw offer from the opponent to decline");
+            return;
+        }
+
+        if let Err(e) = redis_layer.hset(&format!("game:{}", game.game_id), "draw_offer", &serde_json::to_string(&None::<u32>).unwrap()).await {
+            info!("Error clearing draw offer: {}", e);
+            return;
+        }
+        let _ = redis_layer.publish_game_event(game.game_id, GameEvent::DrawDeclined { user_id: self.user_id }).await;
+    }
+
+    // Resends the full game state to this client only, so it can recover if it has fallen out of sync
+    async fn handle_send_reminder(&self) {
+        let redis_layer = self.redis_layer.lock().await;
+        let game = match redis_layer.get_game(self.game_id).await {
+            Some(game) => game,
+            None => {
+                info!("Failed to retreive game (handle_send_reminder)");
+                return;
+            },
+        };
+        drop(redis_layer);
+
+        self.reply(ServerMessage::GameReminder(format_game_state(self.user_id, game, EventStatus::Reminder))).await;
+    }
+
+    // Tells only the moving client its move was not played, with the position it should roll back to
+    async fn reject_move(&self, game: Option<Game>, reason: MoveRejection) {
+        let game = match game {
+            Some(game) => game,
+            None => match self.redis_layer.lock().await.get_game(self.game_id).await {
+                Some(game) => game,
+                None => {
+                    info!("Failed to retreive game (reject_move)");
+                    return;
+                },
+            },
+        };
+        self.reply(ServerMessage::GameMove(format_move_rejection(self.user_id, game, reason))).await;
+    }
+
+    async fn reply(&self, message: ServerMessage) {
+        let mut sender = self.sender.lock().await;
+        if let Err(e) = sender.send(message.to_ws()).await {
+            info!("Error replying to user {}: {}", self.user_id, e);
+        }
+    }
+
+    async fn handle_offer_rematch(&self) {
+        let redis_layer = self.redis_layer.lock().await;
+        let game = match redis_layer.get_game(self.game_id).await {
+            Some(game) => game,
+            None => {
+                info!("Failed to retreive game (handle_offer_rematch)");
+                return;
+            },
+        };
+        let opponent_id = if game.player_black == self.user_id {game.player_white} else {game.player_black};
+
+        let remaining_secs = rematch_window_remaining(&game);
+        if remaining_secs <= 0 {
+            info!("Invalid! Game {} is not within its rematch window", game.game_id);
+            return;
+        }
+
+        let offer_key = format!("rematch_offer:{}", game.game_id);
+        match redis_layer.get(&offer_key).await.ok().flatten().and_then(|offered_by| offered_by.parse::<u32>().ok()) {
+            Some(offered_by) if offered_by == self.user_id => {
+                info!("Player {} has already offered a rematch", self.user_id);
+                return;
+            },
+            Some(offered_by) if offered_by == opponent_id => {
+                // both players want a rematch, so treat the offer as an acceptance
+                drop(redis_layer);
+                self.handle_accept_rematch().await;
+                return;
+            },
+            _ => (),
+        }
+
+        // the offer lapses with the rematch window
+        if let Err(e) = redis_layer.set_ex(&offer_key, &self.user_id.to_string(), remaining_secs as u64).await {
+            info!("Error storing rematch offer: {}", e);
+            return;
+        }
+        let _ = redis_layer.publish_game_event(game.game_id, GameEvent::RematchOffered { user_id: self.user_id, result: game.result, termination: game.termination }).await;
+    }
+
+    // Starts a new game with the same settings and colours swapped, both players' sockets move over to it
+    async fn handle_accept_rematch(&self) {
+        let redis_layer = self.redis_layer.lock().await;
+        let game = match redis_layer.get_game(self.game_id).await {
+            Some(game) => game,
+            None => {
+                info!("Failed to retreive game (handle_accept_rematch)");
+                return;
+            },
+        };
+        let opponent_id = if game.player_black == self.user_id {game.player_white} else {game.player_black};
+
+        if rematch_window_remaining(&game) <= 0 {
+            info!("Invalid! Game {} is not within its rematch window", game.game_id);
+            return;
+        }
+
+        let offer_key = format!("rematch_offer:{}", game.game_id);
+        if redis_layer.get(&offer_key).await.ok().flatten() != Some(opponent_id.to_string()) {
+            info!("Invalid! No rematch offer from the opponent to accept");
+            return;
+        }
+        // taking the offer means only one rematch can be created from it
+        if !matches!(redis_layer.get_del(&offer_key).await, Ok(Some(_))) {
+            return;
+        }
+
+        for player in [game.player_white, game.player_black] {
+            if redis_layer.hget(&format!("user:{}", player), "game_id").await.is_some() {
+                info!("Player {} has already started another game, cancelling rematch of game {}", player, game.game_id);
+                return;
+            }
+        }
+
+        let settings = GameSettings { time_control: game.time_control, rated: game.rated, variant: game.variant };
+        // create_game points both user:{id} mappings at the new game
+        match matchmaking::create_game(game.player_black, game.player_white, ColourPreference::White, &settings, &redis_layer).await {
+            Some(rematch_id) => {
+                info!("created rematch {} of game {}", rematch_id, game.game_id);
+                let _ = redis_layer.publish_game_event(game.game_id, GameEvent::RematchAccepted { game_id: rematch_id }).await;
+            },
+            None => info!("Failed to create rematch of game {}", game.game_id),
+        }
+    }
+
+    // A player may claim a draw once the current position has occurred three times, or after 50 moves without a capture or pawn move
+    async fn handle_claim_draw(&self) {
+        let redis_layer = self.redis_layer.lock().await;
+        let game = match redis_layer.get_game(self.game_id).await {
+            Some(game) => game,
+            None => {
+                info!("Failed to retreive game (handle_claim_draw)");
+                return;
+            },
+        };
+
+        if game.is_over() {
+            info!("Invalid! Game {} has already finished", game.game_id);
+            return;
+        }
+
+        let board: Board = Board::from_fen(&game.board_state).expect("Failed to load board state");
+
+        let termination = match claimable_draw(&board, &game.position_history) {
+            Some(termination) => termination,
+            None => {
+                info!("Invalid! Player {} cannot claim a draw in this position", self.user_id);
+                return;
+            },
+        };
+
+        finish_game(&redis_layer, &game, GameResult::Draw, termination).await;
     }
 
 }
 
-fn construct_bit_move(parsed_move: Arc<EventData>, board: &Board) -> Result<BitMove, String> {
-    let parsed_move = &parsed_move.this_move.clone().unwrap();
-    let from = &parsed_move.from; //handle better
-    let to = &parsed_move.to;
-    let flags: MoveFlag = match parsed_move.flags.as_str() { //handle unwrap better
-        "n" => MoveFlag::QuietMove,
-        "c" => MoveFlag::Capture { ep_capture: false },
-        "b" => MoveFlag::DoublePawnPush,
-        "np" => MoveFlag::Promotion {
-            capture: parsed_move.captured.is_some(),
-            prom: piece_type_from_str(parsed_move.promotion.as_deref().unwrap())
+// Resolves the client's move against the legal moves in the position, it can be sent as UCI, SAN or a chess.js move
+fn construct_bit_move(parsed_move: &MoveRequest, board: &Board) -> Result<BitMove, MoveRejection> {
+    if let Some(uci) = &parsed_move.uci {
+        if !notation::is_uci(uci) {
+            return Err(MoveRejection::MalformedPayload);
+        }
+        return notation::parse_uci(board, uci).ok_or(MoveRejection::IllegalMove);
+    }
+    if let Some(san) = &parsed_move.san {
+        return notation::parse_san(board, san).ok_or(MoveRejection::IllegalMove);
+    }
+
+    let parsed_move = parsed_move.this_move.as_ref().ok_or(MoveRejection::MalformedPayload)?;
+    if square_to_index(&parsed_move.from).is_none() || square_to_index(&parsed_move.to).is_none() {
+        return Err(MoveRejection::MalformedPayload);
+    }
+    // chess.js flags can be combined, eg: cp for a capture with promotion
+    if parsed_move.flags.is_empty() || !parsed_move.flags.chars().all(|flag| "nbecpkq".contains(flag)) {
+        return Err(MoveRejection::MalformedPayload);
+    }
+    let promotion = match (parsed_move.flags.contains('p'), parsed_move.promotion.as_deref().map(str::to_lowercase)) {
+        (true, Some(piece)) if ["n", "b", "r", "q"].contains(&piece.as_str()) => piece,
+        (false, None) => String::new(),
+        _ => return Err(MoveRejection::MalformedPayload),
+    };
+
+    // matched against the legal moves as UCI, which (like chess.js) puts a castling king on its own square rather than the rook's
+    let uci = format!("{}{}{}", parsed_move.from, parsed_move.to, promotion);
+    notation::parse_uci(board, &uci).ok_or_else(|| {
+        info!("move is not valid!");
+        MoveRejection::IllegalMove
+    })
+}
+
+
+// Checks whether the position on the board has ended the game, draws by repetition or move count here are automatic (not claimed)
+fn board_outcome(board: &Board, position_history: &[u64]) -> Option<(GameResult, Termination)> {
+    if board.checkmate() {
+        // the side to move has been mated
+        let result = if board.turn() == Player::White {GameResult::BlackWins} else {GameResult::WhiteWins};
+        Some((result, Termination::Checkmate))
+    } else if !board.in_check() && board.generate_moves().is_empty() {
+        // checked by hand, pleco's stalemate() is also true after 50 half-moves without a capture or pawn move
+        Some((GameResult::Draw, Termination::Stalemate))
+    } else if insufficient_material(board) {
+        Some((GameResult::Draw, Termination::InsufficientMaterial))
+    } else if repetitions(board, position_history) >= 5 {
+        Some((GameResult::Draw, Termination::FivefoldRepetition))
+    } else if board.rule_50() >= 150 {
+        Some((GameResult::Draw, Termination::SeventyFiveMoveRule))
+    } else {
+        None
+    }
+}
+
+// The draw a player may claim in the position, if any
+fn claimable_draw(board: &Board, position_history: &[u64]) -> Option<Termination> {
+    if repetitions(board, position_history) >= 3 {
+        Some(Termination::ThreefoldRepetition)
+    } else if board.rule_50() >= 100 {
+        Some(Termination::FiftyMoveRule)
+    } else {
+        None
+    }
+}
+
+// The outcome if the side to move has run out of time at now_ms
+fn flag_fall(clock: &Clock, board: &Board, now_ms: i64) -> Option<(GameResult, Termination)> {
+    let to_move = board.turn();
+    if clock.remaining_ms(to_move, to_move, now_ms) > 0 {
+        return None;
+    }
+    Some(timeout_outcome(board, to_move))
+}
+
+// A flag fall loses the game, unless the opponent could never deliver mate
+fn timeout_outcome(board: &Board, flagged: Player) -> (GameResult, Termination) {
+    if !has_mating_material(board, flagged.other_player()) {
+        return (GameResult::Draw, Termination::TimeoutVsInsufficientMaterial);
+    }
+    let result = if flagged == Player::White {GameResult::BlackWins} else {GameResult::WhiteWins};
+    (result, Termination::Timeout)
+}
+
+// Whether the player has enough material left to ever checkmate (a lone minor piece cannot)
+fn has_mating_material(board: &Board, player: Player) -> bool {
+    if board.count_piece(player, PieceType::P) > 0
+        || board.count_piece(player, PieceType::R) > 0
+        || board.count_piece(player, PieceType::Q) > 0 {
+        return true;
+    }
+    board.count_piece(player, PieceType::N) + board.count_piece(player, PieceType::B) >= 2
+}
+
+// Background task which ends games where the player to move has run out of time
+pub async fn clock_watcher() {
+    let redis_layer = RedisLayer::new().await;
+
+    // games started before their deadlines were indexed would otherwise never flag
+    for game_id in redis_layer.zrange("active_games", 0, -1).await.unwrap_or_default() {
+        let game = match game_id.parse::<u32>().ok() {
+            Some(game_id) => match redis_layer.get_game(game_id).await {
+                Some(game) => game,
+                None => continue,
+            },
+            None => continue,
+        };
+        if let Ok(board) = Board::from_fen(&game.board_state) {
+            track_clock_deadline(&redis_layer, game.game_id, &game.clock, board.turn()).await;
+        }
+    }
+
+    loop {
+        tokio::time::sleep(CLOCK_WATCHER_INTERVAL).await;
+
+        let now_ms = Utc::now().timestamp_millis();
+
+        let game_ids = match redis_layer.zrangebyscore(CLOCK_DEADLINES, f64::NEG_INFINITY, now_ms as f64).await {
+            Ok(game_ids) => game_ids,
+            Err(e) => {
+                info!("Failed to fetch expired clocks for clock watcher: {}", e);
+                continue;
+            }
+        };
+
+        for game_id in game_ids {
+            let game = match game_id.parse::<u32>().ok() {
+                Some(game_id) => match redis_layer.get_game(game_id).await {
+                    Some(game) => game,
+                    None => {
+                        let _ = redis_layer.zrem(CLOCK_DEADLINES, &game_id.to_string()).await;
+                        continue;
+                    },
+                },
+                None => {
+                    let _ = redis_layer.zrem(CLOCK_DEADLINES, &game_id).await;
+                    continue;
+                },
+            };
+
+            let board = match Board::from_fen(&game.board_state) {
+                Ok(board) => board,
+                Err(_) => continue,
+            };
+            let to_move = board.turn();
+
+            if game.is_over() || !game.clock.is_running() {
+                let _ = redis_layer.zrem(CLOCK_DEADLINES, &game_id).await;
+                continue;
+            }
+
+            if let Some((result, termination)) = flag_fall(&game.clock, &board, now_ms) {
+                info!("flag fell for {:?} in game {}", to_move, game.game_id);
+                finish_game(&redis_layer, &game, result, termination).await;
+            } else {
+                // a move was made after the deadline was read
+                track_clock_deadline(&redis_layer, game.game_id, &game.clock, to_move).await;
+            }
+        }
+    }
+}
+
+// Records when the side to move runs out of time, for the clock watcher
+pub async fn track_clock_deadline(redis_layer: &RedisLayer, game_id: u32, clock: &Clock, to_move: Player) {
+    if let Some(deadline_ms) = clock.deadline_ms(to_move) {
+        if let Err(e) = redis_layer.zadd(CLOCK_DEADLINES, &game_id.to_string(), deadline_ms as f64).await {
+            info!("Failed to track clock deadline for game {}: {}", game_id, e);
+        }
+    }
+}
+
+// Number of times the current position appears in the game's history (including the current occurrence)
+fn repetitions(board: &Board, position_history: &[u64]) -> usize {
+    let key = board.zobrist();
+    position_history.iter().filter(|&&previous| previous == key).count()
+}
+
+// Neither side can checkmate: bare kings, a single minor piece, or only bishops all on the same colour squares
+fn insufficient_material(board: &Board) -> bool {
+    let heavy_pieces = board.piece_bb_both_players(PieceType::P)
+        | board.piece_bb_both_players(PieceType::R)
+        | board.piece_bb_both_players(PieceType::Q);
+    if heavy_pieces.is_not_empty() {
+        return false;
+    }
+
+    let knights = board.piece_bb_both_players(PieceType::N);
+    let bishops = board.piece_bb_both_players(PieceType::B);
+    let minor_pieces = (knights | bishops).count_bits();
+
+    if minor_pieces <= 1 {
+        return true;
+    }
+
+    knights.is_empty() && ((bishops & BitBoard::DARK_SQUARES).is_empty() || (bishops & BitBoard::LIGHT_SQUARES).is_empty())
+}
+
+// Seconds left to arrange a rematch of a finished game, zero or less once it has closed (or the game is still going)
+fn rematch_window_remaining(game: &Game) -> i64 {
+    if game.result.is_none() {
+        return 0;
+    }
+    game.game_ended + REMATCH_GRACE_SECS - Utc::now().timestamp()
+}
+
+// Marks a game as finished, updates both players' stats, removes the game from the active pool and notifies subscribers
+pub async fn finish_game(redis_layer: &RedisLayer, game: &Game, result: GameResult, termination: Termination) {
+    if claim_game(redis_layer, game.game_id).await {
+        settle_game(redis_layer, game, result, termination).await;
+    }
+}
+
+// Removing the game from the active pool is what claims it, so a game can only be finished once
+// (eg: a flag fall noticed by the clock watcher at the same time as a resignation).
+// Anything announcing how the game ended should only be published once this returns true
+pub async fn claim_game(redis_layer: &RedisLayer, game_id: u32) -> bool {
+    match redis_layer.zrem("active_games", &game_id.to_string()).await {
+        Ok(0) => {
+            info!("game {} has already been finished", game_id);
+            return false;
         },
-        "k" => MoveFlag::Castle { king_side: true },
-        "q" => MoveFlag::Castle { king_side: false },
-        "e" => MoveFlag::Capture { ep_capture: true },
-        _ => MoveFlag::QuietMove,  // Fallback if no match
+        Ok(_) => info!("removed game {} from active game pool!", game_id),
+        Err(e) => info!("Failed to remove game from active games!, {}", e)
+    }
+    let _ = redis_layer.zrem(CLOCK_DEADLINES, &game_id.to_string()).await;
+    true
+}
+
+// Records the result of a game already claimed with claim_game
+pub async fn settle_game(redis_layer: &RedisLayer, game: &Game, result: GameResult, termination: Termination) {
+
+    let fields = vec![
+        ("result".to_string(), serde_json::to_string(&Some(result)).unwrap()),
+        ("termination".to_string(), serde_json::to_string(&Some(termination)).unwrap()),
+        ("game_ended".to_string(), Utc::now().timestamp().to_string()),
+    ];
+
+    if let Err(e) = redis_layer.hset_multiple(&format!("game:{}", game.game_id), &fields).await {
+        info!("Error setting game result: {}", e);
+        return;
+    }
+
+    let (white_stat, black_stat) = match result {
+        GameResult::WhiteWins => ("wins", "losses"),
+        GameResult::BlackWins => ("losses", "wins"),
+        GameResult::Draw => ("draws", "draws"),
     };
+    let _ = redis_layer.hincr(&format!("player_stats:{}", game.player_white), white_stat).await;
+    let _ = redis_layer.hincr(&format!("player_stats:{}", game.player_black), black_stat).await;
 
-    let info: PreMoveInfo = PreMoveInfo {
-        src: SQ(square_to_index(from).unwrap()),
-        dst: SQ(square_to_index(to).unwrap()),
-        flags,
+    // casual games still count towards wins / draws / losses, but leave ratings alone
+    let rating_updates = if game.rated {
+        update_ratings(redis_layer, game, result).await
+    } else {
+        Vec::new()
     };
-    
-    let bmove: BitMove = BitMove::init(info);
-    if board.generate_moves().into_iter().collect::<Vec<BitMove>>().contains(&bmove) {
-        return Ok(bmove);
+    let rating_change = |player: u32| rating_updates.iter()
+        .find(|(user_id, _, _)| *user_id == player)
+        .map(|(_, before, after)| RatingChange::new(before, after));
+    let game_over = GameEvent::GameOver {
+        result: Some(result),
+        termination: Some(termination),
+        rating_change_white: rating_change(game.player_white),
+        rating_change_black: rating_change(game.player_black),
+    };
+
+    let _ = redis_layer.del(&format!("user:{}", game.player_white)).await;
+    let _ = redis_layer.del(&format!("user:{}", game.player_black)).await;
+    let _ = redis_layer.del(&format!("connections:{}", game.game_id)).await;
+
+    let _ = redis_layer.publish_game_event(game.game_id, game_over).await;
+
+    archive_game(redis_layer, game.game_id, rating_updates).await;
+}
+
+// Calls off a game which never got going, nobody wins or loses and nothing is archived
+pub async fn abort_game(redis_layer: &RedisLayer, game: &Game) {
+    // claimed the same way as finish_game, so a game can't be both aborted and finished
+    if !claim_game(redis_layer, game.game_id).await {
+        return;
     }
-    else {
-        info!("move is not valid!");
-        return Err("Invalid Move".to_string());
+    info!("aborting game {}", game.game_id);
+
+    let fields = vec![
+        ("termination".to_string(), serde_json::to_string(&Some(Termination::Aborted)).unwrap()),
+        ("game_ended".to_string(), Utc::now().timestamp().to_string()),
+    ];
+    if let Err(e) = redis_layer.hset_multiple(&format!("game:{}", game.game_id), &fields).await {
+        info!("Error aborting game {}: {}", game.game_id, e);
     }
+
+    let _ = redis_layer.del(&format!("user:{}", game.player_white)).await;
+    let _ = redis_layer.del(&format!("user:{}", game.player_black)).await;
+    let _ = redis_layer.del(&format!("connections:{}", game.game_id)).await;
+    let _ = redis_layer.del(&format!("game_readiness:{}", game.game_id)).await;
+
+    let _ = redis_layer.publish_game_event(game.game_id, GameEvent::GameAborted).await;
+    expire_game_events(redis_layer, game.game_id).await;
 }
 
+// Applies the result to both players' ratings and records the change on the game, returning (user_id, before, after) per player
+async fn update_ratings(redis_layer: &RedisLayer, game: &Game, result: GameResult) -> Vec<(u32, Rating, Rating)> {
+    let white_score = match result {
+        GameResult::WhiteWins => 1.0,
+        GameResult::BlackWins => 0.0,
+        GameResult::Draw => 0.5,
+    };
 
-fn handle_offer_draw() {
+    match redis_layer.update_ratings(game.player_white, game.player_black, white_score).await {
+        Ok(((white_before, white_after), (black_before, black_after))) => {
+            let fields = vec![
+                ("rating_change_white".to_string(), serde_json::to_string(&Some(RatingChange::new(&white_before, &white_after))).unwrap()),
+                ("rating_change_black".to_string(), serde_json::to_string(&Some(RatingChange::new(&black_before, &black_after))).unwrap()),
+            ];
+            let _ = redis_layer.hset_multiple(&format!("game:{}", game.game_id), &fields).await;
+            vec![(game.player_white, white_before, white_after), (game.player_black, black_before, black_after)]
+        },
+        Err(e) => {
+            info!("Failed to update ratings for game {}: {}", game.game_id, e);
+            Vec::new()
+        }
+    }
 }
 
-fn handle_accept_draw() {
+// Copies a finished game from redis into the games / game_moves tables, along with any rating changes it caused
+async fn archive_game(redis_layer: &RedisLayer, game_id: u32, rating_updates: Vec<(u32, Rating, Rating)>) {
+    let game = match redis_layer.get_game(game_id).await {
+        Some(game) => game,
+        None => {
+            info!("Failed to retreive game {} for archiving", game_id);
+            return;
+        }
+    };
+    let pgn = pgn::format_pgn(&game);
+
+    // the mysql driver is blocking, so keep it off the async runtime
+    let save_result = task::spawn_blocking(move || {
+        let mut conn = databaselayer::connect_to_db().map_err(|e| e.to_string())?;
+        databaselayer::save_finished_game(&mut conn, &game, &pgn).map_err(|e| e.to_string())?;
+        for (user_id, before, after) in rating_updates {
+            databaselayer::save_rating_history(&mut conn, user_id, game.game_id, &before, &after, game.game_ended).map_err(|e| e.to_string())?;
+        }
+        Ok::<(), String>(())
+    }).await;
+
+    match save_result {
+        Ok(Ok(())) => {
+            info!("archived game {} to the database", game_id);
+            // the moves are in the database now, readers still catching up see the gap and are resent the whole game
+            if let Err(e) = redis_layer.trim_game_events(game_id, 1, GAME_EVENTS_EXPIRY_SECS).await {
+                info!("Failed to trim events for game {}: {}", game_id, e);
+            }
+        },
+        Ok(Err(e)) => {
+            info!("Failed to archive game {}: {}", game_id, e);
+            expire_game_events(redis_layer, game_id).await;
+        },
+        Err(e) => {
+            info!("Archiving task for game {} failed: {}", game_id, e);
+            expire_game_events(redis_layer, game_id).await;
+        },
+    }
 }
 
-fn handle_decline_draw() {
+// Leaves the whole of a game's event stream to expire, for games which end without being archived
+async fn expire_game_events(redis_layer: &RedisLayer, game_id: u32) {
+    if let Err(e) = redis_layer.expire(&format!("game_events:{}", game_id), GAME_EVENTS_EXPIRY_SECS).await {
+        info!("Failed to expire events for game {}: {}", game_id, e);
+    }
 }
 
-fn handle_send_reminder() {
+// Tags a relayed game event with its sequence number, clients send the last one they saw when reconnecting to catch up
+fn with_seq(mut message: ServerMessage, seq: u64) -> ServerMessage {
+    match &mut message {
+        ServerMessage::GameMove(data)
+        | ServerMessage::GameSurrender(data)
+        | ServerMessage::GameOfferDraw(data)
+        | ServerMessage::GameAcceptDraw(data)
+        | ServerMessage::GameDeclineDraw(data)
+        | ServerMessage::GameOver(data)
+        | ServerMessage::GameConnection(data)
+        | ServerMessage::GameOfferRematch(data) => data.seq = Some(seq),
+        _ => (),
+    }
+    message
 }
 
-pub async fn message_sender(sender: Arc<Mutex<SplitSink<WebSocket, Message>>>, user_id: u32, game_id: u32) {
+// Relays the game's events to the client from after_seq onwards until the game is over, returning the new game's id if the players agree a rematch.
+// Stops once a newer connection has taken the player's seat in the game
+pub async fn message_sender(sender: Arc<Mutex<SplitSink<WebSocket, Message>>>, user_id: u32, game_id: u32, after_seq: u64, connection_id: &str) -> Option<u32> {
     //TODO: add functionality for relaying additional types of message
+    // reading the stream blocks this connection, so it can't be shared with anything else
     let redislayer = RedisLayer::new().await;
-    let channel = &format!("game_updates:{}", game_id);
 
-    let pubsub = redislayer.get_pubsub().await;
-    let mut pubsub_stream = pubsub.subscribe(channel).await.unwrap();
+    // the players' colours are all that's read from the game, everything else relayed is carried by its event
+    let game = redislayer.get_game(game_id).await.expect("failed to get game");
+    let player_colour = if game.player_white == user_id {PlayerColour::White} else {PlayerColour::Black};
 
     //send game_initated messge to client:
     {   
         info!("sending game_initiated message...");
-        let game = redislayer.get_game(game_id).await.expect("failed to get game");
-        let player_colour = if game.player_white == user_id {"white"} else {"black"};
-        let message = Message::Text(serde_json::to_string(&json!({
-            "event": "game_initiated",
-            "playercolour": player_colour
-        })).unwrap());
+        let message = ServerMessage::GameInitiated { player_colour: player_colour.clone() }.to_ws();
         let mut sender = sender.lock().await;
         // info!("lock received for sending game_initiated");
         let send_result = sender.send(message).await;
@@ -210,109 +864,292 @@ pub async fn message_sender(sender: Arc<Mutex<SplitSink<WebSocket, Message>>>, u
         drop(sender);
     }
 
-    while let Some(msg) = pubsub_stream.next().await {
-        match msg {
-            Ok(msg) => {
-                let payload = String::from_resp(msg).unwrap();
-                info!("subscriber got: {} for user {}", payload, user_id);
+    // sequence number of the last event relayed, reading carries on from here
+    let mut last_seq = after_seq;
+
+    // set once the game is over, the socket is closed if no rematch has been agreed by then
+    let mut rematch_deadline: Option<tokio::time::Instant> = None;
+
+    loop {
+        // connections:{game_id} is cleared when the game finishes, so only a different id means the player has reconnected elsewhere
+        let seat = redislayer.hget(&format!("connections:{}", game_id), &user_id.to_string()).await;
+        if seat.is_some_and(|seat| seat != connection_id) {
+            info!("user {} has reconnected to game {} elsewhere, stopping the old sender", user_id, game_id);
+            let _ = sender.lock().await.close().await;
+            return None;
+        }
+
+        let block_ms = match rematch_deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(tokio::time::Instant::now()).as_millis() as u64;
+                if remaining == 0 {
+                    let mut sender = sender.lock().await;
+                    match sender.close().await {
+                        Ok(_) => info!("Closed connection after game over!"),
+                        Err(e) => info!("Failed to close connection: {}", e),
+                    };
+                    return None;
+                }
+                remaining.min(GAME_EVENTS_BLOCK_MS)
+            },
+            None => GAME_EVENTS_BLOCK_MS,
+        };
+
+        let updates = match redislayer.read_game_events(game_id, last_seq, block_ms as usize).await {
+            Ok(updates) => updates,
+            Err(e) => {
+                info!("Error reading events for game {}: {}", game_id, e);
+                tokio::time::sleep(Duration::from_millis(block_ms)).await;
+                continue;
+            }
+        };
 
-                let mut event_status = EventStatus::EchoFailure;
-                let parts: Vec<&str> = payload.split(':').collect();
+        for update in updates {
+            // already covered by a resync below
+            if update.seq <= last_seq {
+                continue;
+            }
 
-                let game = redislayer.get_game(game_id).await.expect("failed to get game");
+            // events are missing, eg: the stream was trimmed after the game ended, so the client is sent the whole game instead
+            if update.seq > last_seq + 1 {
+                info!("user {} missed game {} events {} to {}, resyncing", user_id, game_id, last_seq + 1, update.seq - 1);
+                if let Some((current_seq, game)) = redislayer.get_game_snapshot(game_id).await {
+                    last_seq = current_seq;
+                    let aborted = game.termination == Some(Termination::Aborted);
+                    if game.is_over() && rematch_deadline.is_none() {
+                        rematch_deadline = Some(tokio::time::Instant::now() + Duration::from_secs(REMATCH_GRACE_SECS as u64));
+                    }
 
-                let opponent_id = if game.player_black == user_id {game.player_white} else {game.player_black};
+                    let mut sender = sender.lock().await;
+                    let message = if aborted {
+                        ServerMessage::GameAborted { game_id }
+                    } else {
+                        ServerMessage::GameResume(format_game_state(user_id, game, EventStatus::Resumed))
+                    };
+                    if let Err(e) = sender.send(message.to_ws()).await {
+                        info!("Error resyncing user {}! {}", user_id, e);
+                    }
+                    if aborted {
+                        let _ = sender.close().await;
+                        return None;
+                    }
+                    if update.seq <= last_seq {
+                        continue;
+                    }
+                }
+            }
+            last_seq = update.seq;
+            info!("user {} got game {} event {}: {:?}", user_id, game_id, update.seq, update.event);
+
+            // a bad event is logged and skipped, it must not take the player's connection down with it
+            let event = match update.event {
+                Ok(event) => event,
+                Err(e) => {
+                    info!("Malformed event {} for game {}: {}", update.seq, game_id, e);
+                    continue;
+                }
+            };
 
-                let event_status: EventStatus;
-                let message: EventMessage;
-                
-                if parts[0] == "move" && parts[1] == "new" && parts.len() == 3 {
+            let player = player_colour.clone();
+            let message = match event {
+                GameEvent::MoveMade { user_id: mover, this_move, clock } => {
                     // If the player moving isn't the current user, send the move to the client.
-                    event_status = if parts[2].parse::<u32>().unwrap_or(0) != user_id {
+                    let event_status = if mover != user_id {
                         EventStatus::UpdateNewMove
                     } else {
                         EventStatus::EchoSuccess
                     };
-                
-                    message = format_game_move(game, user_id, event_status);
-                
-                } else if parts[0] == "player" && parts[1] == "surrender" && parts.len() == 3 {
-                    // Check if the surrendering player is not the current user.
-                    event_status = if parts[2].parse::<u32>().unwrap_or(0) != user_id {
+                    ServerMessage::GameMove(format_game_move(player, this_move, clock, event_status))
+                },
+                GameEvent::Surrendered { user_id: surrendering, result, termination } => {
+                    let event_status = if surrendering != user_id {
                         EventStatus::OpponentSurrender
                     } else {
                         EventStatus::ConfirmSurrendered
                     };
-                
-                    message = format_surrender(user_id, game, event_status);
-
-                    if parts[2].parse::<u32>().unwrap_or(0) == user_id {
-                        let _ = redislayer.hincr(&format!("user_stats:{}",user_id), "losses").await;
-                    } else if parts[2].parse::<u32>().unwrap_or(0) == opponent_id {
-                        let _ = redislayer.hincr(&format!("user_stats:{}",opponent_id), "wins").await;
+                    ServerMessage::GameSurrender(format_event(player, result, termination, event_status))
+                },
+                // a player's own connection changes are only of interest to their opponent
+                GameEvent::Disconnected { user_id: connecting, .. } | GameEvent::Reconnected { user_id: connecting, .. } if connecting == user_id => continue,
+                GameEvent::Disconnected { clock, .. } => ServerMessage::GameConnection(format_connection(player, clock, EventStatus::OpponentDisconnected)),
+                GameEvent::Reconnected { clock, .. } => ServerMessage::GameConnection(format_connection(player, clock, EventStatus::OpponentReconnected)),
+                GameEvent::DrawOffered { user_id: offering } => {
+                    let event_status = if offering == user_id {EventStatus::ConfirmDrawOffered} else {EventStatus::OpponentOfferedDraw};
+                    ServerMessage::GameOfferDraw(format_event(player, None, None, event_status))
+                },
+                GameEvent::DrawAccepted { .. } => {
+                    ServerMessage::GameAcceptDraw(format_event(player, Some(GameResult::Draw), Some(Termination::Agreement), EventStatus::DrawAgreed))
+                },
+                GameEvent::DrawDeclined { user_id: declining } => {
+                    let event_status = if declining == user_id {EventStatus::ConfirmDrawDeclined} else {EventStatus::OpponentDeclinedDraw};
+                    ServerMessage::GameDeclineDraw(format_event(player, None, None, event_status))
+                },
+                GameEvent::GameOver { result, termination, rating_change_white, rating_change_black } => {
+                    // nothing more will happen in this game, but the players may still want a rematch
+                    rematch_deadline = Some(tokio::time::Instant::now() + Duration::from_secs(REMATCH_GRACE_SECS as u64));
+                    let rating_change = if game.player_white == user_id {rating_change_white} else {rating_change_black};
+                    ServerMessage::GameOver(format_game_over(player, result, termination, rating_change, EventStatus::GameOver))
+                },
+                GameEvent::RematchOffered { user_id: offering, result, termination } => {
+                    let event_status = if offering == user_id {EventStatus::ConfirmRematchOffered} else {EventStatus::OpponentOfferedRematch};
+                    ServerMessage::GameOfferRematch(format_event(player, result, termination, event_status))
+                },
+                GameEvent::RematchAccepted { game_id: rematch_id } => {
+                    let message = ServerMessage::GameRematch { game_id: rematch_id }.to_ws();
+                    let mut sender = sender.lock().await;
+                    if let Err(e) = sender.send(message).await {
+                        info!("Error sending rematch to user {}! {}", user_id, e);
                     }
-                } else if parts[0] == "game" && parts[1] == "close" && parts.len() == 2 {
+                    return Some(rematch_id);
+                },
+                GameEvent::GameAborted => {
+                    let mut sender = sender.lock().await;
+                    if let Err(e) = sender.send(ServerMessage::GameAborted { game_id }.to_ws()).await {
+                        info!("Error sending abort to user {}! {}", user_id, e);
+                    }
+                    let _ = sender.close().await;
+                    return None;
+                },
+                GameEvent::GameClosed => {
                     // Close the WebSocket connection.
                     let mut sender = sender.lock().await;
                     match sender.close().await {
                         Ok(_) => info!("Closed connection!"),
-                        Err(e) => eprint!("Failed to close connection: {}", e),
+                        Err(e) => info!("Failed to close connection: {}", e),
                     };
-                    return; // Exit the function after closing the connection.
-                } else {
-                    // Handle invalid message types
-                    eprint!("Unrecognized message type.");
-                    return;
-                }
-                
-                // Send the message after formatting it into JSON.
-                let message_text = Message::Text(serde_json::to_string(&message).unwrap());
-                let mut sender = sender.lock().await;
-                
-                if let Err(e) = sender.send(message_text).await {
-                    eprint!("Error sending message to user {} from subscriber! {}", user_id, e);
-                }                
+                    return None; // Exit the function after closing the connection.
+                },
+                GameEvent::Unknown => {
+                    info!("Skipping unknown game update {} for user {}", update.seq, user_id);
+                    continue;
+                },
+            };
 
-            },
-            Err(e) => eprintln!("Error!: {}", e)
+            let mut sender = sender.lock().await;
+
+            if let Err(e) = sender.send(with_seq(message, update.seq).to_ws()).await {
+                info!("Error sending message to user {} from subscriber! {}", user_id, e);
+            }
         }
     }
 }
 
-fn format_game_move(game: Game, user_id: u32, event_status: EventStatus) -> EventMessage{
-    EventMessage {
-        event: "game_move".to_string(),
-        data: EventData {
-            player: if game.player_white == user_id {PlayerColour::White} else {PlayerColour::Black},
-            this_move: Some(game.previous_move.unwrap()),
+fn format_game_move(player: PlayerColour, this_move: Move, clock: Clock, event_status: EventStatus) -> EventData {
+    EventData { this_move: Some(this_move), clock: Some(clock), ..format_event(player, None, None, event_status) }
+}
+
+// Payload of a relayed event with the player's side and the result once there is one, eg: a surrender or a draw offer.
+// The other relayed events add their own fields to it
+fn format_event(player: PlayerColour, result: Option<GameResult>, termination: Option<Termination>, event_status: EventStatus) -> EventData {
+    EventData {
+            player,
+            this_move: None,
             status: event_status,
+            result,
+            termination,
+            clock: None,
+            rating_change: None,
+            state: None,
+            fen: None,
+            reason: None,
+            seq: None,
         }
-    }
 }
 
-fn format_surrender(user_id: u32, game: Game, event_status: EventStatus) -> EventMessage {
-    EventMessage {
-        event: "game_surrender".to_string(),
-        data: EventData {
+fn format_game_over(
+    player: PlayerColour,
+    result: Option<GameResult>,
+    termination: Option<Termination>,
+    rating_change: Option<RatingChange>,
+    event_status: EventStatus,
+) -> EventData {
+    EventData { rating_change, ..format_event(player, result, termination, event_status) }
+}
+
+fn format_move_rejection(user_id: u32, game: Game, reason: MoveRejection) -> EventData {
+    EventData {
             player: if game.player_white == user_id {PlayerColour::White} else {PlayerColour::Black},
             this_move: None,
+            status: EventStatus::EchoFailure,
+            result: game.result,
+            termination: game.termination,
+            clock: Some(game.clock),
+            rating_change: None,
+            state: None,
+            fen: Some(game.board_state),
+            reason: Some(reason),
+            seq: None,
+        }
+}
+
+fn format_connection(player: PlayerColour, clock: Option<Clock>, event_status: EventStatus) -> EventData {
+    EventData { clock, ..format_event(player, None, None, event_status) }
+}
+
+fn format_game_state(user_id: u32, game: Game, event_status: EventStatus) -> EventData {
+    let colour = |player: u32| if game.player_white == player {PlayerColour::White} else {PlayerColour::Black};
+    let state = GameState {
+        game_id: game.game_id,
+        fen: game.board_state.clone(),
+        moves: game.moves.clone(),
+        turn: if game.last_moved.0 == game.player_white {PlayerColour::Black} else {PlayerColour::White},
+        player_white: game.player_white,
+        player_black: game.player_black,
+        clock: game.clock,
+        server_time_ms: Utc::now().timestamp_millis(),
+        draw_offer: game.draw_offer.map(colour),
+    };
+    EventData {
+            player: colour(user_id),
+            this_move: game.previous_move.clone(),
             status: event_status,
+            result: game.result,
+            termination: game.termination,
+            clock: Some(game.clock),
+            rating_change: if game.player_white == user_id {game.rating_change_white} else {game.rating_change_black},
+            state: Some(state),
+            fen: None,
+            reason: None,
+            seq: None,
         }
-    }   
 }
 
-fn piece_type_from_str(piece_str: &str) -> PieceType {
-    match piece_str {
-        "p" => PieceType::P,
-        "n" => PieceType::N,
-        "b" => PieceType::B,
-        "r" => PieceType::R,
-        "q" => PieceType::Q,
-        "k" => PieceType::K,
-        _ => PieceType::None, // Default if no match found
+// Full game state sent to a player who has reconnected to a game in progress
+pub fn format_resume(user_id: u32, game: Game) -> Message {
+    ServerMessage::GameResume(format_game_state(user_id, game, EventStatus::Resumed)).to_ws()
+}
+
+// The move as chess.js would describe it, eg: flags "c" and captured "n" for a knight capture
+fn chess_js_move(board: &Board, bit_move: BitMove) -> Move {
+    let flags = if bit_move.is_castle() {
+        if bit_move.is_king_castle() {"k"} else {"q"}
+    } else if bit_move.is_en_passant() {
+        "e"
+    } else if bit_move.is_promo() {
+        if bit_move.is_capture() {"cp"} else {"np"}
+    } else if bit_move.is_double_push().0 {
+        "b"
+    } else if bit_move.is_capture() {
+        "c"
+    } else {
+        "n"
+    };
+
+    Move {
+        from: bit_move.get_src().to_string(),
+        to: king_destination(bit_move),
+        flags: flags.to_string(),
+        captured: if bit_move.is_capture() {Some(board.captured_piece(bit_move).char_lower().to_string())} else {None},
+        promotion: if bit_move.is_promo() {Some(bit_move.promo_piece().char_lower().to_string())} else {None},
     }
 }
 
+// The square the moving piece lands on, pleco stores castling as the king taking its own rook but chess.js wants
+// the king's square (g1 / c1 / g8 / c8), the same as UCI
+fn king_destination(bit_move: BitMove) -> String {
+    notation::move_to_uci(bit_move)[2..4].to_string()
+}
+
 fn square_to_index(square: &str) -> Option<u8> {
     if square.len() != 2 {
         return None;
@@ -321,22 +1158,61 @@ fn square_to_index(square: &str) -> Option<u8> {
     let rank = square.chars().nth(1)?.to_digit(10)?;
     let file_index = (file as u8).checked_sub('a' as u8)?;
     let rank_index = (rank as u8).checked_sub(1)?;
+    if file_index > 7 || rank_index > 7 {
+        return None;
+    }
     Some(rank_index * 8 + file_index)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct EventMessage {
-    event: String, //eg: game_start, game_move, game_surrender
-    data: EventData,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// Payload of every game event sent to clients
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 #[serde(rename_all = "camelCase")]
-struct EventData {
+pub struct EventData {
     player: PlayerColour,
     this_move: Option<Move>, //cant use 'move' word as it is reserved
     status: EventStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<GameResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    termination: Option<Termination>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    clock: Option<Clock>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rating_change: Option<RatingChange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    state: Option<GameState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fen: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<MoveRejection>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>, // position in the game's event stream, only on relayed events
+}
+
+// Why a move was not played, sent back to the moving client along with EchoFailure
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum MoveRejection {
+    NotYourTurn,
+    IllegalMove,
+    MalformedPayload,
+    GameOver,
+    StalePosition, // another move was applied while this one was being checked
+}
+
+// Everything a client needs to rebuild a game from scratch, eg: after reconnecting
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GameState {
+    game_id: u32,
+    fen: String,
+    moves: Vec<PlayedMove>,
+    turn: PlayerColour,
+    player_white: u32,
+    player_black: u32,
+    clock: Clock,
+    server_time_ms: i64, // for the client to work out how much of the running clock has gone
+    draw_offer: Option<PlayerColour>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -346,12 +1222,198 @@ pub struct Game {
     pub player_black: u32,
     pub game_created: i64, //timestamp
     pub game_initiated: i64,
+    pub game_ended: i64, // 0 until the game has finished
     pub last_moved: (u32, i64), // (user_id, timestamp)
     pub board_state: String,
     pub previous_move: Option<Move>,
+    pub draw_offer: Option<u32>, // user_id of the player with a pending draw offer
+    pub result: Option<GameResult>, // None while the game is in progress
+    pub termination: Option<Termination>,
+    pub position_history: Vec<u64>, // zobrist keys of every position reached, for repetition rules
+    pub time_control: TimeControl,
+    pub rated: bool,
+    pub variant: Variant,
+    pub clock: Clock,
+    pub moves: Vec<PlayedMove>, // every move of the game in order
+    pub rating_change_white: Option<RatingChange>, // set once a rated game has finished
+    pub rating_change_black: Option<RatingChange>,
+    pub ply: u32, // half-moves played, moves are only applied against the ply they were checked at
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+impl Game {
+    // Finished or aborted, an aborted game has a termination but no result
+    pub fn is_over(&self) -> bool {
+        self.result.is_some() || self.termination.is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PlayedMove {
+    pub san: String, // eg: Nxf3+
+    pub uci: String, // eg: g1f3
+}
+
+// Everything players choose before a game, used to keep separate matchmaking pools
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GameSettings {
+    pub time_control: TimeControl,
+    pub rated: bool,
+    pub variant: Variant,
+}
+
+impl GameSettings {
+    // eg: matchmaking_pool:standard:3+2:rated
+    pub fn pool_key(&self) -> String {
+        let rated = if self.rated {"rated"} else {"casual"};
+        format!("matchmaking_pool:{}:{}:{}", self.variant.as_str(), self.time_control, rated)
+    }
+}
+
+impl Default for GameSettings {
+    fn default() -> Self {
+        GameSettings { time_control: TimeControl::default(), rated: true, variant: Variant::default() }
+    }
+}
+
+// Side a player asks to play in a challenge or seek
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ColourPreference {
+    White,
+    Black,
+    #[default]
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Variant {
+    #[default]
+    Standard,
+}
+
+impl Variant {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Variant::Standard => "standard",
+        }
+    }
+}
+
+// Time control, written as "{minutes}+{increment seconds}", eg: 3+2
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeControl {
+    pub initial_secs: u32,
+    pub increment_secs: u32,
+}
+
+impl TimeControl {
+    const MAX_MINUTES: u32 = 180;
+    const MAX_INCREMENT_SECS: u32 = 180;
+
+    pub fn parse(time_control: &str) -> Option<Self> {
+        let (minutes, increment) = time_control.split_once('+')?;
+        let minutes: u32 = minutes.trim().parse().ok()?;
+        let increment_secs: u32 = increment.trim().parse().ok()?;
+        if (minutes == 0 && increment_secs == 0) || minutes > Self::MAX_MINUTES || increment_secs > Self::MAX_INCREMENT_SECS {
+            return None;
+        }
+        Some(TimeControl { initial_secs: minutes * 60, increment_secs })
+    }
+}
+
+impl Default for TimeControl {
+    fn default() -> Self {
+        TimeControl { initial_secs: 600, increment_secs: 0 } // 10+0
+    }
+}
+
+impl std::fmt::Display for TimeControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}+{}", self.initial_secs / 60, self.increment_secs)
+    }
+}
+
+// Server-authoritative clock, the side to move's time runs from turn_started_ms
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Clock {
+    pub white_ms: i64,
+    pub black_ms: i64,
+    pub turn_started_ms: i64, // 0 until the game has been initiated
+}
+
+impl Clock {
+    pub fn new(time_control: &TimeControl) -> Self {
+        let initial_ms = time_control.initial_secs as i64 * 1000;
+        Clock { white_ms: initial_ms, black_ms: initial_ms, turn_started_ms: 0 }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.turn_started_ms > 0
+    }
+
+    // Time left for the player at now_ms, counting down the current turn if it is theirs
+    pub fn remaining_ms(&self, player: Player, to_move: Player, now_ms: i64) -> i64 {
+        let stored = if player == Player::White {self.white_ms} else {self.black_ms};
+        if player == to_move && self.is_running() {
+            stored - (now_ms - self.turn_started_ms)
+        } else {
+            stored
+        }
+    }
+
+    // Time (ms) at which the player to move runs out, None before the clock has started
+    pub fn deadline_ms(&self, to_move: Player) -> Option<i64> {
+        if !self.is_running() {
+            return None;
+        }
+        Some(self.turn_started_ms + if to_move == Player::White {self.white_ms} else {self.black_ms})
+    }
+
+    // Stops the mover's clock, adds their increment and starts the opponent's clock
+    pub fn press(&mut self, mover: Player, now_ms: i64, time_control: &TimeControl) {
+        let remaining = self.remaining_ms(mover, mover, now_ms) + time_control.increment_secs as i64 * 1000;
+        if mover == Player::White {
+            self.white_ms = remaining;
+        } else {
+            self.black_ms = remaining;
+        }
+        self.turn_started_ms = now_ms;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum GameResult {
+    #[serde(rename = "1-0")]
+    WhiteWins,
+    #[serde(rename = "0-1")]
+    BlackWins,
+    #[serde(rename = "1/2-1/2")]
+    Draw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Termination {
+    Checkmate,
+    Stalemate,
+    Resignation,
+    Agreement,
+    InsufficientMaterial,
+    ThreefoldRepetition,
+    FivefoldRepetition,
+    FiftyMoveRule,
+    SeventyFiveMoveRule,
+    Timeout,
+    TimeoutVsInsufficientMaterial,
+    Abandonment,
+    Aborted, // called off before it got going, there is no result
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Move {
     pub from: String,
@@ -361,21 +1423,248 @@ pub struct Move {
     pub promotion: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 #[serde(rename_all = "camelCase")]
-enum PlayerColour {
+pub enum PlayerColour {
     White,
     Black,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 #[serde(rename_all = "PascalCase")] // Matches PascalCase used in the JSON
-enum EventStatus {
+pub enum EventStatus {
     EchoSuccess, //after the client makes a move, and the server validates it, send the new game state back with this status
     EchoFailure, //after the client makes a move, and the server INVALIDATES it, send the unchanged game state back with this status
     UpdateNewMove, //after the opponent makes a move, (which has been validated), send the new game state back with this status
     ConfirmSurrendered,
     OpponentSurrender,
+    ConfirmDrawOffered,
+    OpponentOfferedDraw,
+    ConfirmDrawDeclined,
+    OpponentDeclinedDraw,
+    DrawAgreed,
+    ConfirmRematchOffered,
+    OpponentOfferedRematch,
+    GameOver, //the game has finished, the result and termination reason are sent with this status
     Reminder, //if the client asks to be re-sent the game state, send it along with this status
+    Resumed, //the full game state, sent to a player who has reconnected to a game in progress
+    OpponentDisconnected, //the opponent's clock keeps running while they have a chance to reconnect
+    OpponentReconnected,
     ClientMessage,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chess_js(fen: &str, uci: &str) -> Move {
+        let board = Board::from_fen(fen).unwrap();
+        chess_js_move(&board, notation::parse_uci(&board, uci).expect("move should be legal"))
+    }
+
+    fn assert_move(played: Move, from: &str, to: &str, flags: &str, captured: Option<&str>, promotion: Option<&str>) {
+        assert_eq!(played.from, from);
+        assert_eq!(played.to, to);
+        assert_eq!(played.flags, flags);
+        assert_eq!(played.captured.as_deref(), captured);
+        assert_eq!(played.promotion.as_deref(), promotion);
+    }
+
+    #[test]
+    fn chess_js_castling_lands_on_the_kings_square() {
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+        assert_move(chess_js(fen, "e1g1"), "e1", "g1", "k", None, None);
+        assert_move(chess_js(fen, "e1c1"), "e1", "c1", "q", None, None);
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1";
+        assert_move(chess_js(fen, "e8g8"), "e8", "g8", "k", None, None);
+        assert_move(chess_js(fen, "e8c8"), "e8", "c8", "q", None, None);
+    }
+
+    #[test]
+    fn chess_js_pawn_moves() {
+        assert_move(chess_js("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", "e2e4"), "e2", "e4", "b", None, None);
+        assert_move(chess_js("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 2", "e5d6"), "e5", "d6", "e", Some("p"), None);
+        assert_move(chess_js("3rk3/4P3/8/8/8/8/8/4K3 w - - 0 1", "e7d8n"), "e7", "d8", "cp", Some("r"), Some("n"));
+        assert_move(chess_js("3r3k/4P3/8/8/8/8/8/4K3 w - - 0 1", "e7e8q"), "e7", "e8", "np", None, Some("q"));
+        assert_move(chess_js("4k3/8/8/3p4/4P3/8/8/4K3 w - - 0 1", "e4d5"), "e4", "d5", "c", Some("p"), None);
+    }
+
+    fn from_chess_js(fen: &str, from: &str, to: &str, flags: &str, promotion: Option<&str>) -> Result<BitMove, MoveRejection> {
+        let board = Board::from_fen(fen).unwrap();
+        let this_move = Move {
+            from: from.to_string(),
+            to: to.to_string(),
+            flags: flags.to_string(),
+            captured: None,
+            promotion: promotion.map(str::to_string),
+        };
+        construct_bit_move(&MoveRequest { this_move: Some(this_move), ..Default::default() }, &board)
+    }
+
+    #[test]
+    fn chess_js_castling_is_accepted_on_the_kings_square() {
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+        assert!(from_chess_js(fen, "e1", "g1", "k", None).unwrap().is_king_castle());
+        assert!(from_chess_js(fen, "e1", "c1", "q", None).unwrap().is_queen_castle());
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1";
+        assert!(from_chess_js(fen, "e8", "g8", "k", None).unwrap().is_king_castle());
+        assert!(from_chess_js(fen, "e8", "c8", "q", None).unwrap().is_queen_castle());
+    }
+
+    #[test]
+    fn chess_js_moves_round_trip_through_the_server() {
+        for (fen, uci) in [
+            ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1g1"),
+            ("3rk3/4P3/8/8/8/8/8/4K3 w - - 0 1", "e7d8q"),
+            ("3r3k/4P3/8/8/8/8/8/4K3 w - - 0 1", "e7e8n"),
+            ("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 2", "e5d6"),
+            ("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", "e2e4"),
+        ] {
+            let sent = chess_js(fen, uci);
+            let promotion = sent.promotion.as_deref();
+            let received = from_chess_js(fen, &sent.from, &sent.to, &sent.flags, promotion).unwrap();
+            assert_eq!(notation::move_to_uci(received), uci);
+        }
+    }
+
+    #[test]
+    fn chess_js_moves_with_bad_flags_or_promotions_are_malformed() {
+        let fen = "3rk3/4P3/8/8/8/8/8/4K3 w - - 0 1";
+        assert!(matches!(from_chess_js(fen, "e7", "d8", "x", Some("q")), Err(MoveRejection::MalformedPayload)));
+        assert!(matches!(from_chess_js(fen, "e7", "d8", "", Some("q")), Err(MoveRejection::MalformedPayload)));
+        assert!(matches!(from_chess_js(fen, "e7", "d8", "cp", None), Err(MoveRejection::MalformedPayload)));
+        assert!(matches!(from_chess_js(fen, "e7", "d8", "cp", Some("k")), Err(MoveRejection::MalformedPayload)));
+        assert!(matches!(from_chess_js(fen, "e7", "d8", "c", Some("q")), Err(MoveRejection::MalformedPayload)));
+        assert!(matches!(from_chess_js(fen, "e7", "i8", "cp", Some("q")), Err(MoveRejection::MalformedPayload)));
+        assert!(matches!(from_chess_js(fen, "e1", "e3", "n", None), Err(MoveRejection::IllegalMove)));
+    }
+
+    const FIVE_PLUS_TWO: TimeControl = TimeControl { initial_secs: 300, increment_secs: 2 };
+    const STARTED_MS: i64 = 1_700_000_000_000;
+
+    fn started_clock() -> Clock {
+        Clock { turn_started_ms: STARTED_MS, ..Clock::new(&FIVE_PLUS_TWO) }
+    }
+
+    #[test]
+    fn pressing_the_clock_charges_the_mover_and_adds_their_increment() {
+        let mut clock = started_clock();
+        clock.press(Player::White, STARTED_MS + 7_500, &FIVE_PLUS_TWO);
+        assert_eq!(clock, Clock { white_ms: 300_000 - 7_500 + 2_000, black_ms: 300_000, turn_started_ms: STARTED_MS + 7_500 });
+
+        // black's time now runs, white's stays where the press left it
+        let now_ms = STARTED_MS + 10_000;
+        assert_eq!(clock.remaining_ms(Player::Black, Player::Black, now_ms), 300_000 - 2_500);
+        assert_eq!(clock.remaining_ms(Player::White, Player::Black, now_ms), 294_500);
+        assert_eq!(clock.deadline_ms(Player::Black), Some(STARTED_MS + 7_500 + 300_000));
+    }
+
+    #[test]
+    fn flag_falls_once_the_side_to_move_reaches_zero() {
+        let board = Board::from_fen("4k3/8/8/8/8/8/8/3QK3 b - - 0 1").unwrap();
+        let clock = started_clock();
+        let deadline_ms = clock.deadline_ms(Player::Black).unwrap();
+        assert_eq!(flag_fall(&clock, &board, deadline_ms - 1), None);
+        assert_eq!(flag_fall(&clock, &board, deadline_ms), Some((GameResult::WhiteWins, Termination::Timeout)));
+
+        // white only has a king left, so can't win on time
+        let board = Board::from_fen("3qk3/8/8/8/8/8/8/4K3 b - - 0 1").unwrap();
+        assert_eq!(flag_fall(&clock, &board, deadline_ms), Some((GameResult::Draw, Termination::TimeoutVsInsufficientMaterial)));
+    }
+
+    #[test]
+    fn flag_never_falls_before_the_clock_starts() {
+        let board = Board::start_pos();
+        let clock = Clock::new(&FIVE_PLUS_TWO);
+        assert_eq!(clock.deadline_ms(Player::White), None);
+        assert_eq!(flag_fall(&clock, &board, STARTED_MS + 3_600_000), None);
+    }
+
+    // Plays the moves from the starting position, returning the board and every position reached (as handle_move keeps it)
+    fn play(ucis: &[&str]) -> (Board, Vec<u64>) {
+        let mut board = Board::start_pos();
+        let mut position_history = vec![board.zobrist()];
+        for uci in ucis {
+            board.apply_move(notation::parse_uci(&board, uci).unwrap_or_else(|| panic!("{} should be legal", uci)));
+            position_history.push(board.zobrist());
+        }
+        (board, position_history)
+    }
+
+    fn outcome_of(fen: &str) -> Option<(GameResult, Termination)> {
+        let board = Board::from_fen(fen).unwrap();
+        board_outcome(&board, &[board.zobrist()])
+    }
+
+    #[test]
+    fn checkmate_is_won_by_the_side_which_delivered_it() {
+        let (board, history) = play(&["f2f3", "e7e5", "g2g4", "d8h4"]);
+        assert_eq!(board_outcome(&board, &history), Some((GameResult::BlackWins, Termination::Checkmate)));
+        let (board, history) = play(&["e2e4", "e7e5", "f1c4", "b8c6", "d1h5", "g8f6", "h5f7"]);
+        assert_eq!(board_outcome(&board, &history), Some((GameResult::WhiteWins, Termination::Checkmate)));
+    }
+
+    #[test]
+    fn stalemate_is_a_draw() {
+        assert_eq!(outcome_of("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1"), Some((GameResult::Draw, Termination::Stalemate)));
+        assert_eq!(outcome_of("k7/2Q5/1K6/8/8/8/8/8 b - - 0 1"), Some((GameResult::Draw, Termination::Stalemate)));
+    }
+
+    #[test]
+    fn play_continues_without_mate_or_stalemate() {
+        let (board, history) = play(&["e2e4", "e7e5", "d1h5", "b8c6"]);
+        assert_eq!(board_outcome(&board, &history), None);
+        // in check but with a way out
+        assert_eq!(outcome_of("4k3/8/8/8/8/8/4q3/4K3 w - - 0 1"), None);
+        // a long run of quiet moves is not a stalemate
+        assert_eq!(outcome_of("4k3/8/8/8/8/8/4P3/R3K3 w - - 60 80"), None);
+    }
+
+    const KNIGHT_SHUFFLE: [&str; 4] = ["g1f3", "g8f6", "f3g1", "f6g8"];
+
+    #[test]
+    fn repeating_the_position_three_times_can_be_claimed_and_five_times_ends_the_game() {
+        // the starting position occurs once more after every shuffle
+        let (board, history) = play(&KNIGHT_SHUFFLE[..]);
+        assert_eq!(claimable_draw(&board, &history), None);
+        assert_eq!(board_outcome(&board, &history), None);
+
+        let (board, history) = play(&KNIGHT_SHUFFLE.repeat(2));
+        assert_eq!(claimable_draw(&board, &history), Some(Termination::ThreefoldRepetition));
+        assert_eq!(board_outcome(&board, &history), None);
+
+        let (board, history) = play(&KNIGHT_SHUFFLE.repeat(4));
+        assert_eq!(board_outcome(&board, &history), Some((GameResult::Draw, Termination::FivefoldRepetition)));
+    }
+
+    #[test]
+    fn fifty_moves_without_a_capture_or_pawn_move_can_be_claimed_and_seventy_five_end_the_game() {
+        let board = Board::from_fen("4k3/8/8/8/8/8/4P3/R3K3 w - - 99 80").unwrap();
+        assert_eq!(claimable_draw(&board, &[board.zobrist()]), None);
+
+        let board = Board::from_fen("4k3/8/8/8/8/8/4P3/R3K3 w - - 100 80").unwrap();
+        assert_eq!(claimable_draw(&board, &[board.zobrist()]), Some(Termination::FiftyMoveRule));
+        assert_eq!(board_outcome(&board, &[board.zobrist()]), None);
+
+        assert_eq!(outcome_of("4k3/8/8/8/8/8/4P3/R3K3 w - - 150 100"), Some((GameResult::Draw, Termination::SeventyFiveMoveRule)));
+    }
+
+    #[test]
+    fn positions_neither_side_can_win_are_drawn() {
+        for fen in [
+            "4k3/8/8/8/8/8/8/4K3 w - - 0 1", // bare kings
+            "4k3/8/8/8/8/8/8/2B1K3 w - - 0 1", // king and bishop
+            "4k3/8/8/8/8/8/8/1N2K3 b - - 0 1", // king and knight
+            "2b1k3/8/8/8/8/8/8/3BK3 w - - 0 1", // bishops on the same colour squares
+        ] {
+            assert_eq!(outcome_of(fen), Some((GameResult::Draw, Termination::InsufficientMaterial)), "{}", fen);
+        }
+        for fen in [
+            "3bk3/8/8/8/8/8/8/3BK3 w - - 0 1", // bishops on opposite colours can still mate
+            "4k3/8/8/8/8/8/8/1NB1K3 w - - 0 1",
+            "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1",
+            "4k3/8/8/8/8/8/8/R3K3 w - - 0 1",
+        ] {
+            assert_eq!(outcome_of(fen), None, "{}", fen);
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use axum::{
-    middleware, routing::{get, post, options}, Router
+    middleware, routing::{delete, get, post, options}, Router
 };
 use tokio::task;
 use tower_http::cors::{Any, CorsLayer};
@@ -9,14 +9,27 @@ use std::env;
 
 mod websocket;
 mod matchmaking;
+mod challenges;
+mod seeks;
+mod events;
+mod protocol;
 mod utils;
 mod authlayer;
 mod databaselayer;
 mod redislayer;
 mod gameserver;
+mod games;
+mod notation;
+mod pgn;
+mod rating;
 use authlayer::validate_jwt_sub;
+use challenges::{accept_challenge, cancel_challenge, challenge_options, create_challenge, decline_challenge, get_challenge, list_challenges};
+use gameserver::clock_watcher;
+use games::{game_pgn, past_games};
+use protocol::protocol_schema;
+use seeks::{accept_seek, cancel_seek, create_seek, list_seeks, seek_feed_handler, seek_options};
 use websocket::websocket_handler;
-use matchmaking::{bot_handler, match_maker, matchmaking_handler, matchmaking_options, matchmaking_status, player_stats};
+use matchmaking::{bot_handler, cancel_matchmaking, match_maker, matchmaking_handler, matchmaking_options, matchmaking_status, player_stats};
 
 mod testing;
 use testing::test_setup;
@@ -36,12 +49,40 @@ async fn main() {
         match_maker().await;
     });
 
+    // Spawning the concurrent thread to end games on time
+    task::spawn(async {
+        clock_watcher().await;
+    });
+
     let app = Router::new()
         .route("/ws", get(websocket_handler))
+        .route("/protocol", get(protocol_schema))
         .route("/matchmaking", post(matchmaking_handler))
         .route("/matchmaking", options(matchmaking_options))
+        .route("/matchmaking", delete(cancel_matchmaking))
         .route("/playerstats", options(player_stats))
         .route("/playerstats", get(player_stats))
+        .route("/games", options(past_games))
+        .route("/games", get(past_games))
+        .route("/games/:id/pgn", get(game_pgn))
+        .route("/seeks", options(seek_options))
+        .route("/seeks", post(create_seek))
+        .route("/seeks", get(list_seeks))
+        .route("/seeks/ws", get(seek_feed_handler))
+        .route("/seeks/:id", delete(cancel_seek))
+        .route("/seeks/:id", options(seek_options))
+        .route("/seeks/:id/accept", post(accept_seek))
+        .route("/seeks/:id/accept", options(seek_options))
+        .route("/challenges", options(challenge_options))
+        .route("/challenges", post(create_challenge))
+        .route("/challenges", get(list_challenges))
+        .route("/challenges/:id", get(get_challenge))
+        .route("/challenges/:id", delete(cancel_challenge))
+        .route("/challenges/:id", options(challenge_options))
+        .route("/challenges/:id/accept", post(accept_challenge))
+        .route("/challenges/:id/accept", options(challenge_options))
+        .route("/challenges/:id/decline", post(decline_challenge))
+        .route("/challenges/:id/decline", options(challenge_options))
         // .route("/bot", post(bot_handler))
         .route("/test", get(test_setup))
         .route("/matchmaking", get(matchmaking_status).layer(middleware::from_fn(validate_jwt_sub)));
@@ -1,11 +1,67 @@
-use axum::{http::StatusCode, response::{IntoResponse, Json, Response}};
-use http::{header, Method, Request};
-use hyper::Body;
+use axum::{http::StatusCode, response::{IntoResponse, Json}};
+use http::{Method, Request};
 use log::info;
 use pleco::Board;
+use rand::Rng;
 use chrono::Utc;
 use serde_json::json;
-use crate::{authlayer, gameserver::Game, redislayer::{self, RedisLayer}};
+use std::{collections::HashMap, future::Future, time::Duration};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use crate::{authlayer, gameserver::{Clock, ColourPreference, Game, GameSettings, TimeControl, Variant}, redislayer::{self, RedisLayer}, utils::cors_response};
+
+// players start out only matched within this rating difference, which widens the longer they wait
+const INITIAL_RATING_WINDOW: f64 = 100.0;
+const RATING_WINDOW_GROWTH_PER_SEC: f64 = 10.0;
+const MAX_MATCHMAKING_WAIT_SECS: i64 = 60;
+
+// pool key -> settings json, for every pool players have joined
+const MATCHMAKING_POOLS: &str = "matchmaking_pools";
+
+// list the match maker blocks on, pushed to whenever a player joins a pool
+const MATCHMAKING_EVENTS: &str = "matchmaking_events";
+// how often the match maker re-checks every pool, as waiting players' rating windows widen
+const MATCHMAKING_RESCAN_SECS: f64 = 1.0;
+// shortest wait redis accepts for BLPOP (0 means forever)
+const MIN_BLPOP_TIMEOUT_SECS: f64 = 0.001;
+
+// number of recent games considered when balancing colours
+const COLOUR_HISTORY_LENGTH: isize = 10;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MatchmakingEvent {
+    pool_key: String,
+    queued_at_ms: i64, // to log how long it takes the match maker to react
+}
+
+// Body of POST /matchmaking, every field is optional and falls back to a rated standard 10+0 game
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchmakingRequest {
+    // time_control is still accepted from clients written before the rename
+    #[serde(default, alias = "time_control", skip_serializing_if = "Option::is_none")]
+    time_control: Option<String>, // eg: "3+2"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rated: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    variant: Option<Variant>,
+}
+
+impl MatchmakingRequest {
+    pub fn into_settings(self) -> Result<GameSettings, String> {
+        let defaults = GameSettings::default();
+        let time_control = match self.time_control {
+            Some(time_control) => TimeControl::parse(&time_control)
+                .ok_or_else(|| format!("Invalid time control: {}, expected minutes+increment eg: 3+2", time_control))?,
+            None => defaults.time_control,
+        };
+        Ok(GameSettings {
+            time_control,
+            rated: self.rated.unwrap_or(defaults.rated),
+            variant: self.variant.unwrap_or(defaults.variant),
+        })
+    }
+}
 
 pub async fn matchmaking_options(req: Request<hyper::Body>) -> impl IntoResponse {
     info!("hit matchmaking options");
@@ -32,44 +88,187 @@ pub async fn matchmaking_handler(req: Request<hyper::Body>) -> impl IntoResponse
         }
     };
 
-    // Logic to return early if user is already in matchmaking pool
-    let score: Option<f64> = match redislayer.zscore("matchmaking_pool", &user_id.to_string()).await {
-        Ok(score) => score,
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
         Err(e) => {
             return cors_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                json!({"message": format!("encountered error fetching matchmaking pool from redis: {}", e)}),
+                StatusCode::BAD_REQUEST,
+                json!({"message": format!("Failed to read request body: {}", e)}),
             );
         }
     };
 
-    if score.is_some() {
-        return cors_response(
+    let matchmaking_request: MatchmakingRequest = if body.is_empty() {
+        MatchmakingRequest::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(matchmaking_request) => matchmaking_request,
+            Err(e) => {
+                return cors_response(
+                    StatusCode::BAD_REQUEST,
+                    json!({"message": format!("Invalid matchmaking request: {}", e)}),
+                );
+            }
+        }
+    };
+
+    let settings = match matchmaking_request.into_settings() {
+        Ok(settings) => settings,
+        Err(message) => return cors_response(StatusCode::BAD_REQUEST, json!({"message": message})),
+    };
+
+    match join_matchmaking(user_id, &settings, &redislayer).await {
+        Ok(pool_key) => cors_response(
+            StatusCode::OK,
+            json!({
+                "message": "User has been added to matchmaking pool successfully",
+                "pool": pool_key,
+                "instructions": "Query GET /matchmaking for an update on matchmaking status",
+            }),
+        ),
+        Err(JoinError::AlreadyQueued) => cors_response(
             StatusCode::BAD_REQUEST,
             json!({"message": "User already in matchmaking pool"}),
-        );
+        ),
+        Err(JoinError::Redis(e)) => {
+            eprintln!("Error adding to Redis ZSET: {:?}", e);
+            cors_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"message": "Failed to add to Redis matchmaking pool"}),
+            )
+        }
+    }
+}
+
+pub enum JoinError {
+    AlreadyQueued,
+    Redis(redis::RedisError),
+}
+
+// Adds the user to the pool for their settings and wakes the match maker, returning the pool key
+pub async fn join_matchmaking(user_id: u32, settings: &GameSettings, redislayer: &RedisLayer) -> Result<String, JoinError> {
+    // Logic to return early if user is already in a matchmaking pool
+    let current_pool: Option<String> = redislayer.get(&format!("matchmaking_entry:{}", user_id)).await.map_err(JoinError::Redis)?;
+    if current_pool.is_some() {
+        return Err(JoinError::AlreadyQueued);
     }
 
-    // Adding user to the pool
+    let pool_key = settings.pool_key();
+    let _ = redislayer.set(&format!("matchmaking_entry:{}", user_id), &pool_key).await;
+
     let timestamp: i64 = Utc::now().timestamp();
-    match redislayer.zadd("matchmaking_pool", &user_id.to_string(), timestamp as f64).await {
-        Ok(()) => {
-            return cors_response(
-                StatusCode::OK,
-                json!({
-                    "message": "User has been added to matchmaking pool successfully",
-                    "instructions": "Query GET /matchmaking for an update on matchmaking status",
-                }),
-            );
+    if let Err(e) = redislayer.zadd(&pool_key, &user_id.to_string(), timestamp as f64).await {
+        let _ = redislayer.del(&format!("matchmaking_entry:{}", user_id)).await;
+        return Err(JoinError::Redis(e));
+    }
+    // listed after the player is in it, the match maker drops pools it finds empty
+    let _ = redislayer.hset(MATCHMAKING_POOLS, &pool_key, &serde_json::to_string(settings).unwrap()).await;
+
+    // wake the match maker up for this pool
+    let event = MatchmakingEvent { pool_key: pool_key.clone(), queued_at_ms: Utc::now().timestamp_millis() };
+    if let Err(e) = redislayer.lpush(MATCHMAKING_EVENTS, &serde_json::to_string(&event).unwrap()).await {
+        eprintln!("Error notifying match maker: {:?}", e);
+    }
+    Ok(pool_key)
+}
+
+pub enum LeaveOutcome {
+    Removed,
+    NotQueued,
+    Matched(Option<u32>), // game id, None while the game is still being created
+}
+
+// Takes the user out of whichever pool they are waiting in, unless the match maker got to them first
+pub async fn leave_matchmaking(user_id: u32, redislayer: &RedisLayer) -> Result<LeaveOutcome, redis::RedisError> {
+    let entry_key = format!("matchmaking_entry:{}", user_id);
+    let pool_key: Option<String> = redislayer.get(&entry_key).await?;
+
+    if let Some(pool_key) = &pool_key {
+        if redislayer.zrem(pool_key, &user_id.to_string()).await? == 1 {
+            let _ = redislayer.del(&entry_key).await;
+            return Ok(LeaveOutcome::Removed);
         }
-        Err(e) => {
-            eprintln!("Error adding to Redis ZSET: {:?}", e);
-            return cors_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                json!({"message": "Failed to add to Redis matchmaking pool"}),
-            );
+        // otherwise the match maker has already taken the user out of the pool to create a game
+    }
+
+    match redislayer.hget(&format!("user:{}", user_id), "game_id").await.and_then(|game_id| game_id.parse::<u32>().ok()) {
+        Some(game_id) => Ok(LeaveOutcome::Matched(Some(game_id))),
+        None if pool_key.is_some() => Ok(LeaveOutcome::Matched(None)),
+        None => Ok(LeaveOutcome::NotQueued),
+    }
+}
+
+// The game a challenge or seek is for
+pub struct OfferedGame {
+    pub offered_by: u32,
+    pub colour: ColourPreference, // the offering player's side
+    pub settings: GameSettings,
+}
+
+// Why a challenge or seek could not be accepted
+pub enum AcceptError {
+    Taken, // another accept (or a cancel) claimed the offer first
+    Conflict(String),
+    Failed(String),
+}
+
+// Starts the game for a challenge or seek when accepted_by accepts it, this takes priority
+// over waiting in the anonymous pool. Only the accept which claims the offer goes ahead, the offer is restored if
+// either player turns out to be in a game already and closed once the game is going ahead.
+// The futures are only awaited when their step is reached
+pub async fn accept_offer(
+    offer: OfferedGame,
+    accepted_by: u32,
+    claim: impl Future<Output = Result<bool, redis::RedisError>>,
+    restore: impl Future<Output = ()>,
+    close: impl Future<Output = ()>,
+    redislayer: &RedisLayer,
+) -> Result<u32, AcceptError> {
+    match claim.await {
+        Ok(true) => (),
+        Ok(false) => return Err(AcceptError::Taken),
+        Err(e) => return Err(AcceptError::Failed(format!("encountered error accepting: {}", e))),
+    }
+
+    for player in [offer.offered_by, accepted_by] {
+        if redislayer.hget(&format!("user:{}", player), "game_id").await.is_some() {
+            restore.await;
+            return Err(AcceptError::Conflict(format!("User {} is already playing a game", player)));
         }
     }
+    for player in [offer.offered_by, accepted_by] {
+        if let Ok(LeaveOutcome::Matched(_)) = leave_matchmaking(player, redislayer).await {
+            restore.await;
+            return Err(AcceptError::Conflict(format!("User {} has already been matched into a game", player)));
+        }
+    }
+    close.await;
+
+    create_game(offer.offered_by, accepted_by, offer.colour, &offer.settings, redislayer).await
+        .ok_or_else(|| AcceptError::Failed("Failed to create game".to_string()))
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueStatus {
+    pub pool: String,
+    pub position: u64, // 1 for the longest waiting player
+    pub players_waiting: u64,
+    pub waiting_secs: i64,
+}
+
+pub async fn queue_status(user_id: u32, redislayer: &RedisLayer) -> Option<QueueStatus> {
+    let pool = redislayer.get(&format!("matchmaking_entry:{}", user_id)).await.ok()??;
+    let queued_at = redislayer.zscore(&pool, &user_id.to_string()).await.ok()??;
+    let position = redislayer.zrank(&pool, &user_id.to_string()).await.ok()?? + 1;
+    let players_waiting = redislayer.zcard(&pool).await.ok()?;
+
+    Some(QueueStatus {
+        pool,
+        position,
+        players_waiting,
+        waiting_secs: Utc::now().timestamp() - queued_at as i64,
+    })
 }
 
 pub async fn bot_handler() {
@@ -101,9 +300,67 @@ pub async fn player_stats(req: Request<hyper::Body>) -> impl IntoResponse {
     let wins = redislayer.hget(&format!("player_stats:{}",user_id), "wins").await.unwrap_or("0".to_string());
     let draws = redislayer.hget(&format!("player_stats:{}",user_id), "draws").await.unwrap_or("0".to_string());
     let losses = redislayer.hget(&format!("player_stats:{}",user_id), "losses").await.unwrap_or("0".to_string());
+    let rating = match redislayer.get_rating(user_id).await {
+        Ok(rating) => rating,
+        Err(e) => {
+            return cors_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"message": format!("encountered error fetching rating from redis: {}", e)}),
+            );
+        }
+    };
     info!("wins: {}", wins);
-    return cors_response(StatusCode::OK, json!({"wins": wins, "draws": draws, "losses": losses}));
+    cors_response(StatusCode::OK, json!({
+        "wins": wins,
+        "draws": draws,
+        "losses": losses,
+        "rating": rating.rating.round(),
+        "rating_deviation": rating.deviation.round(),
+        "provisional": rating.is_provisional(),
+    }))
+
+}
+
+pub async fn cancel_matchmaking(req: Request<hyper::Body>) -> impl IntoResponse {
+    info!("DELETE /matchmaking hit!");
+
+    let user_id = match authlayer::get_jwt_sub(&req).await {
+        Ok(id) => id,
+        Err(e) => {
+            return cors_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"message": format!("encountered error: {}", e.1)}),
+            );
+        }
+    };
+
+    let redislayer = RedisLayer::new().await;
 
+    match leave_matchmaking(user_id, &redislayer).await {
+        Ok(LeaveOutcome::Removed) => cors_response(StatusCode::OK, json!({
+            "message": "User has been removed from the matchmaking pool",
+            "removed": true,
+        })),
+        Ok(LeaveOutcome::Matched(Some(game_id))) => cors_response(StatusCode::CONFLICT, json!({
+            "message": format!("User has already been matched into game: {}", game_id),
+            "removed": false,
+            "game_id": game_id,
+            "instructions": "Open a websocket request to the server at /ws"
+        })),
+        Ok(LeaveOutcome::Matched(None)) => cors_response(StatusCode::CONFLICT, json!({
+            "message": "User has already been matched, the game is being created",
+            "removed": false,
+            "instructions": "Query GET /matchmaking for an update on matchmaking status",
+        })),
+        Ok(LeaveOutcome::NotQueued) => cors_response(StatusCode::OK, json!({
+            "message": "User was not in the matchmaking pool",
+            "removed": false,
+        })),
+        Err(e) => cors_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({"message": format!("encountered error removing user from matchmaking pool: {}", e)}),
+        ),
+    }
 }
 
 pub async fn matchmaking_status(req: Request<hyper::Body>) -> impl IntoResponse {
@@ -120,7 +377,10 @@ pub async fn matchmaking_status(req: Request<hyper::Body>) -> impl IntoResponse
         Some(game_id) => game_id,
         None => {
             // accepted 202 means response is still processing
-            return cors_response(StatusCode::ACCEPTED, json!({"message": "User is waiting in the matchmaking pool..."}));
+            return cors_response(StatusCode::ACCEPTED, json!({
+                "message": "User is waiting in the matchmaking pool...",
+                "queue": queue_status(user_id, &redislayer).await,
+            }));
         }
     };
 
@@ -137,81 +397,403 @@ pub async fn matchmaking_status(req: Request<hyper::Body>) -> impl IntoResponse
 
 pub async fn match_maker() {
     let redislayer = RedisLayer::new().await;
+    // BLPOP holds its connection until it returns, so it gets one to itself
+    let events = RedisLayer::new().await;
+
+    // every pool is re-checked on this schedule however many events arrive, so busy pools can't starve the rest
+    let rescan_interval = Duration::from_secs_f64(MATCHMAKING_RESCAN_SECS);
+    let mut next_rescan = tokio::time::Instant::now();
 
     loop {
-        let player_count: i32 = redislayer.zcard("matchmaking_pool").await.unwrap().try_into().unwrap();
-        if player_count < 2 {
-            continue;
+        let wait_secs = next_rescan.saturating_duration_since(tokio::time::Instant::now()).as_secs_f64();
+        // a BLPOP timeout of 0 would block forever, so skip the wait when a rescan is already due
+        let event = if wait_secs < MIN_BLPOP_TIMEOUT_SECS {
+            None
+        } else {
+            match events.blpop(MATCHMAKING_EVENTS, wait_secs).await {
+                Ok(event) => event.and_then(|event| serde_json::from_str::<MatchmakingEvent>(&event).ok()),
+                Err(e) => {
+                    eprintln!("Error waiting for matchmaking events: {}", e);
+                    tokio::time::sleep(rescan_interval).await;
+                    continue;
+                }
+            }
+        };
+
+        if let Some(event) = &event {
+            info!("match maker woken for {} after {}ms", event.pool_key, Utc::now().timestamp_millis() - event.queued_at_ms);
+        }
+
+        let rescan = tokio::time::Instant::now() >= next_rescan;
+        if rescan {
+            next_rescan = tokio::time::Instant::now() + rescan_interval;
         }
-        let result: redis::RedisResult<Vec<(String, f64)>> = redislayer.zpopmin("matchmaking_pool", 2).await;
-
-        match result {
-            Ok(players) => {
-                if !players.is_empty() {
-                    let valid_players: Vec<(String, f64)> = players.into_iter()
-                        .filter(|(user_id, _score)| !user_id.is_empty())  // Check if user_id is non-empty
-                        .collect();
-    
-                    if !valid_players.is_empty() {
-                        for (user_id, score) in &valid_players {
-                            println!("Popped User: {}, Score (timestamp): {}", user_id, score);
-                        }
-                        create_game(valid_players[0].0.parse().unwrap(),
-                        valid_players[1].0.parse().unwrap(), &redislayer).await;
-                    }
+
+        let pools: HashMap<String, String> = match redislayer.hgetall(MATCHMAKING_POOLS).await {
+            Ok(pools) => pools,
+            Err(e) => {
+                eprintln!("Error reading matchmaking pools: {}", e);
+                continue;
+            }
+        };
+
+        for (pool_key, settings) in pools {
+            // a new player only affects their own pool, the rest wait for the next rescan
+            if !rescan && event.as_ref().is_some_and(|event| event.pool_key != pool_key) {
+                continue;
+            }
+            let settings: GameSettings = match serde_json::from_str(&settings) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    eprintln!("Invalid settings for matchmaking pool {}: {}", pool_key, e);
+                    continue;
                 }
+            };
+            match_pool(&pool_key, &settings, &redislayer).await;
+        }
+    }
+}
+
+// Pairs up the players waiting in one pool, all of whom want the same game settings
+async fn match_pool(pool_key: &str, settings: &GameSettings, redislayer: &RedisLayer) {
+    let pool: Vec<(String, f64)> = match redislayer.zrange_withscores(pool_key, 0, -1).await {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("Error reading matchmaking pool {}: {}", pool_key, e);
+            return;
+        }
+    };
+    if pool.is_empty() {
+        // otherwise every time control anyone has ever asked for would be rescanned forever
+        if let Err(e) = redislayer.remove_empty_pool(MATCHMAKING_POOLS, pool_key).await {
+            eprintln!("Error removing empty matchmaking pool {}: {}", pool_key, e);
+        }
+        return;
+    }
+    if pool.len() < 2 {
+        return;
+    }
+
+    // pool is ordered by score, so candidates are ordered by how long they have been waiting
+    let mut candidates: Vec<(u32, f64, i64)> = Vec::new();
+    for (user_id, queued_at) in pool {
+        let user_id: u32 = match user_id.parse() {
+            Ok(user_id) => user_id,
+            Err(_) => continue,
+        };
+        let rating = match redislayer.get_rating(user_id).await {
+            Ok(rating) => rating.rating,
+            Err(e) => {
+                eprintln!("Error fetching rating for user {}: {}", user_id, e);
+                continue;
             }
+        };
+        candidates.push((user_id, rating, queued_at as i64));
+    }
+
+    let queued_at: HashMap<u32, i64> = candidates.iter().map(|&(user_id, _, queued_at)| (user_id, queued_at)).collect();
+
+    for (player1, player2) in pair_players(&candidates, Utc::now().timestamp()) {
+        // every instance runs a match maker, so both players are claimed in one atomic step,
+        // if either has been taken by another instance (or has cancelled) neither is removed
+        match redislayer.claim_pair(pool_key, player1, player2).await {
+            Ok(true) => (),
+            Ok(false) => {
+                info!("users {} and {} are no longer both waiting in {}", player1, player2, pool_key);
+                continue;
+            },
             Err(e) => {
-                eprintln!("Error performing ZPOPMIN on matchmaking pool: {}", e);
+                eprintln!("Error claiming users {} and {} from {}: {}", player1, player2, pool_key, e);
+                continue;
             }
         }
+
+        info!("Paired users: {} and {} in {}", player1, player2, pool_key);
+        // entries are only cleared once the game exists, so a cancel in between can see the user was matched
+        if create_game(player1, player2, ColourPreference::Random, settings, redislayer).await.is_none() {
+            // put both players back where they were in the queue, their entries still point at this pool
+            info!("Failed to create a game for users {} and {}, returning them to {}", player1, player2, pool_key);
+            for player in [player1, player2] {
+                if let Err(e) = redislayer.zadd(pool_key, &player.to_string(), queued_at[&player] as f64).await {
+                    eprintln!("Error returning user {} to {}: {}", player, pool_key, e);
+                }
+            }
+            // the pool may have been dropped while it was empty
+            let _ = redislayer.hset(MATCHMAKING_POOLS, pool_key, &serde_json::to_string(settings).unwrap()).await;
+            continue;
+        }
+        let _ = redislayer.del(&format!("matchmaking_entry:{}", player1)).await;
+        let _ = redislayer.del(&format!("matchmaking_entry:{}", player2)).await;
+    }
+}
+
+// Largest rating difference a player will accept after waiting for wait_secs
+fn rating_window(wait_secs: i64) -> f64 {
+    if wait_secs >= MAX_MATCHMAKING_WAIT_SECS {
+        return f64::INFINITY; // nobody waits forever, take any opponent
+    }
+    INITIAL_RATING_WINDOW + RATING_WINDOW_GROWTH_PER_SEC * wait_secs.max(0) as f64
+}
+
+// Greedily pairs the longest-waiting players with the closest rated opponent inside either player's window
+// candidates are (user_id, rating, queued_at) ordered by queued_at
+fn pair_players(candidates: &[(u32, f64, i64)], now: i64) -> Vec<(u32, u32)> {
+    let mut paired = vec![false; candidates.len()];
+    let mut pairs = Vec::new();
+
+    for i in 0..candidates.len() {
+        if paired[i] {
+            continue;
+        }
+        let (user_id, rating, queued_at) = candidates[i];
+        let window = rating_window(now - queued_at);
+
+        let opponent = (i + 1..candidates.len())
+            .filter(|&j| !paired[j])
+            .filter(|&j| {
+                let (_, opponent_rating, opponent_queued_at) = candidates[j];
+                (rating - opponent_rating).abs() <= window.max(rating_window(now - opponent_queued_at))
+            })
+            .min_by(|&a, &b| {
+                (rating - candidates[a].1).abs().total_cmp(&(rating - candidates[b].1).abs())
+            });
+
+        if let Some(j) = opponent {
+            paired[i] = true;
+            paired[j] = true;
+            pairs.push((user_id, candidates[j].0));
+        }
     }
-        
+    pairs
+}
+
+// (white, black) for a new game, see choose_colours
+async fn assign_colours(player1: u32, player2: u32, preference: ColourPreference, redislayer: &RedisLayer) -> (u32, u32) {
+    let balance1 = colour_balance(player1, redislayer).await;
+    let balance2 = colour_balance(player2, redislayer).await;
+    choose_colours(player1, player2, preference, balance1, balance2, rand::thread_rng().gen_bool(0.5))
+}
+
+// (white, black), an explicit preference from player1 wins, otherwise whoever has played white more often
+// recently (the higher colour balance) gets black, with coin_toss giving player1 white when both are even
+fn choose_colours(player1: u32, player2: u32, preference: ColourPreference, balance1: i64, balance2: i64, coin_toss: bool) -> (u32, u32) {
+    let player1_white = match preference {
+        ColourPreference::White => true,
+        ColourPreference::Black => false,
+        ColourPreference::Random if balance1 == balance2 => coin_toss,
+        ColourPreference::Random => balance1 < balance2,
+    };
+    if player1_white {(player1, player2)} else {(player2, player1)}
+}
+
+// games as white minus games as black over the player's recent history
+async fn colour_balance(user_id: u32, redislayer: &RedisLayer) -> i64 {
+    let history = redislayer.lrange(&format!("colour_history:{}", user_id), 0, -1).await.unwrap_or_default();
+    history.iter().map(|colour| if colour == "white" {1} else {-1}).sum()
+}
 
+async fn record_colour(user_id: u32, colour: &str, redislayer: &RedisLayer) {
+    let key = format!("colour_history:{}", user_id);
+    let _ = redislayer.lpush(&key, colour).await;
+    let _ = redislayer.ltrim(&key, 0, COLOUR_HISTORY_LENGTH - 1).await;
 }
 
-async fn create_game(player1: u32, player2: u32, redislayer: &RedisLayer) {
+// player1's colour preference is honoured when given, eg: from a challenge or seek
+pub async fn create_game(player1: u32, player2: u32, preference: ColourPreference, settings: &GameSettings, redislayer: &RedisLayer) -> Option<u32> {
     let game_id: u32 = match redislayer.incr("game_id_counter").await {
         Ok(id) => id.try_into().unwrap(),
-        Err(_) => return (), //handle this..
+        Err(_) => return None, //handle this..
     };
 
+    let (white, black) = assign_colours(player1, player2, preference, redislayer).await;
+
     info!("game id counter: {}", game_id);
 
     let now = Utc::now().timestamp();
+    let board = Board::start_pos();
 
     let game = Game {
         game_id: game_id,
-        player_white: player1,
-        player_black: player2,
+        player_white: white,
+        player_black: black,
         game_created: now,
         game_initiated: 0,
-        last_moved: (player2, now), //so white starts
-        board_state: Board::start_pos().fen().to_string(),
+        game_ended: 0,
+        last_moved: (black, now), //so white starts
+        board_state: board.fen().to_string(),
         previous_move: None,
+        draw_offer: None,
+        result: None,
+        termination: None,
+        position_history: vec![board.zobrist()],
+        time_control: settings.time_control,
+        rated: settings.rated,
+        variant: settings.variant,
+        clock: Clock::new(&settings.time_control),
+        moves: Vec::new(),
+        rating_change_white: None,
+        rating_change_black: None,
+        ply: 0,
     };
 
-    let _ = redislayer.hset_game(&game).await; //create game hashmap
-
-    //these might need to be awaited so we dont make things in redis before others are available
-    let _ = redislayer.zadd("active_games", &game_id.to_string(), now as f64).await; //active game pool
+    // the players are only pointed at the game once it exists, so a failed write leaves them free to be paired again
+    if let Err(e) = redislayer.hset_game(&game).await { //create game hashmap
+        info!("Failed to store game {}: {}", game_id, e);
+        return None;
+    }
+    if let Err(e) = redislayer.zadd("active_games", &game_id.to_string(), now as f64).await { //active game pool
+        info!("Failed to add game {} to the active games: {}", game_id, e);
+        let _ = redislayer.del(&format!("game:{}", game_id)).await;
+        return None;
+    }
+    record_colour(white, "white", redislayer).await;
+    record_colour(black, "black", redislayer).await;
 
     //user->game mapping
     let res = redislayer.hset(&format!("user:{}",player1), "game_id", &game_id.to_string()).await;
     info!("result: {:?}", res);
     let _ = redislayer.hset(&format!("user:{}",player2), "game_id", &game_id.to_string()).await;
 
-    info!("created game: {} for players: {}, {}", game_id, player1, player2);
+    //let players waiting in the websocket lobby know their game is ready
+    let _ = redislayer.publish(&format!("lobby:{}", player1), &format!("match:found:{}", game_id)).await;
+    let _ = redislayer.publish(&format!("lobby:{}", player2), &format!("match:found:{}", game_id)).await;
+
+    info!("created game: {} for white: {}, black: {}", game_id, white, black);
+    Some(game_id)
 }
 
-fn cors_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
-    let json_body = body.to_string();
-    Response::builder()
-        .status(status)
-        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*") // Allow requests from any origin
-        .header(header::ACCESS_CONTROL_ALLOW_HEADERS, "*")
-        .header(header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, OPTIONS")
-        .header(header::CONTENT_TYPE, "application/json") // Set Content-Type to JSON
-        .body(Body::from(json_body))
-        .unwrap() // Or handle error more gracefully
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    #[test]
+    fn an_explicit_colour_preference_wins_over_the_balance() {
+        for coin_toss in [true, false] {
+            assert_eq!(choose_colours(1, 2, ColourPreference::White, 5, -5, coin_toss), (1, 2));
+            assert_eq!(choose_colours(1, 2, ColourPreference::Black, -5, 5, coin_toss), (2, 1));
+        }
+    }
+
+    #[test]
+    fn the_player_who_has_had_white_more_often_gets_black() {
+        for coin_toss in [true, false] {
+            assert_eq!(choose_colours(1, 2, ColourPreference::Random, 3, -1, coin_toss), (2, 1));
+            assert_eq!(choose_colours(1, 2, ColourPreference::Random, -2, 0, coin_toss), (1, 2));
+        }
+        // evenly balanced players toss for it
+        assert_eq!(choose_colours(1, 2, ColourPreference::Random, 2, 2, true), (1, 2));
+        assert_eq!(choose_colours(1, 2, ColourPreference::Random, 2, 2, false), (2, 1));
+    }
+
+    #[test]
+    fn rating_window_widens_with_the_wait_until_anyone_will_do() {
+        assert_eq!(rating_window(0), INITIAL_RATING_WINDOW);
+        assert_eq!(rating_window(-5), INITIAL_RATING_WINDOW); // queued_at a little ahead of this server's clock
+        assert_eq!(rating_window(10), INITIAL_RATING_WINDOW + 10.0 * RATING_WINDOW_GROWTH_PER_SEC);
+        assert!(rating_window(MAX_MATCHMAKING_WAIT_SECS - 1).is_finite());
+        assert_eq!(rating_window(MAX_MATCHMAKING_WAIT_SECS), f64::INFINITY);
+    }
+
+    #[test]
+    fn pairs_the_closest_rated_opponent() {
+        let candidates = [(1, 1500.0, NOW), (2, 1580.0, NOW), (3, 1510.0, NOW)];
+        assert_eq!(pair_players(&candidates, NOW), vec![(1, 3)]);
+    }
+
+    #[test]
+    fn pairs_further_apart_players_the_longer_either_has_waited() {
+        let gap = INITIAL_RATING_WINDOW + 50.0;
+        assert_eq!(pair_players(&[(1, 1500.0, NOW), (2, 1500.0 + gap, NOW)], NOW), vec![]);
+        // 5s of waiting is enough for either player
+        assert_eq!(pair_players(&[(1, 1500.0, NOW - 5), (2, 1500.0 + gap, NOW)], NOW), vec![(1, 2)]);
+        assert_eq!(pair_players(&[(1, 1500.0, NOW), (2, 1500.0 + gap, NOW - 5)], NOW), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn pairs_anyone_after_the_longest_wait() {
+        let just_before = [(1, 1000.0, NOW - MAX_MATCHMAKING_WAIT_SECS + 1), (2, 2500.0, NOW)];
+        assert_eq!(pair_players(&just_before, NOW), vec![]);
+        let at_the_cutoff = [(1, 1000.0, NOW - MAX_MATCHMAKING_WAIT_SECS), (2, 2500.0, NOW)];
+        assert_eq!(pair_players(&at_the_cutoff, NOW), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn never_pairs_a_player_twice_in_one_pass() {
+        let candidates: Vec<(u32, f64, i64)> = (1..=5).map(|user_id| (user_id, 1500.0, NOW - 30)).collect();
+        let pairs = pair_players(&candidates, NOW);
+        assert_eq!(pairs, vec![(1, 2), (3, 4)]);
+        let mut players: Vec<u32> = pairs.iter().flat_map(|&(player1, player2)| [player1, player2]).collect();
+        players.sort();
+        players.dedup();
+        assert_eq!(players.len(), pairs.len() * 2);
+    }
+
+    const PLAYERS: u32 = 40;
+    const MATCH_MAKERS: usize = 4;
+    const FIRST_TEST_USER: u32 = 4_100_000_000;
+
+    async fn cleanup(players: &[u32], pool_key: &str, redislayer: &RedisLayer) {
+        for &player in players {
+            if let Some(game_id) = redislayer.hget(&format!("user:{}", player), "game_id").await {
+                let _ = redislayer.del(&format!("game:{}", game_id)).await;
+                let _ = redislayer.zrem("active_games", &game_id).await;
+            }
+            for key in ["user", "matchmaking_entry", "colour_history"] {
+                let _ = redislayer.del(&format!("{}:{}", key, player)).await;
+            }
+            let _ = redislayer.zrem(pool_key, &player.to_string()).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "needs a redis server at REDIS_URL"]
+    async fn concurrent_match_makers_neither_lose_nor_double_book_players() {
+        let redislayer = RedisLayer::new().await;
+        let settings = GameSettings {
+            time_control: TimeControl { initial_secs: 178 * 60, increment_secs: 178 },
+            rated: false,
+            variant: Variant::Standard,
+        };
+        let pool_key = settings.pool_key();
+        let players: Vec<u32> = (0..PLAYERS).map(|i| FIRST_TEST_USER + i).collect();
+        cleanup(&players, &pool_key, &redislayer).await;
+
+        let match_makers: Vec<_> = (0..MATCH_MAKERS).map(|_| tokio::spawn(match_maker())).collect();
+        for &player in &players {
+            assert!(join_matchmaking(player, &settings, &redislayer).await.is_ok(), "user {} failed to join", player);
+        }
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while redislayer.zcard(&pool_key).await.unwrap() > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        // let any game being created when the pool emptied finish
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        for match_maker in match_makers {
+            match_maker.abort();
+        }
+
+        let mut games: HashMap<u32, Vec<u32>> = HashMap::new();
+        for &player in &players {
+            let game_id = redislayer.hget(&format!("user:{}", player), "game_id").await;
+            let game_id: u32 = game_id.unwrap_or_else(|| panic!("user {} was lost", player)).parse().unwrap();
+            games.entry(game_id).or_default().push(player);
+            // create_game records a colour for every game a player is put in
+            let games_played = redislayer.lrange(&format!("colour_history:{}", player), 0, -1).await.unwrap().len();
+            assert_eq!(games_played, 1, "user {} was put in {} games", player, games_played);
+        }
+
+        assert_eq!(games.len() as u32, PLAYERS / 2);
+        for (game_id, mut players_in_game) in games {
+            let game = redislayer.get_game(game_id).await.unwrap();
+            let mut expected = vec![game.player_white, game.player_black];
+            players_in_game.sort();
+            expected.sort();
+            assert_eq!(players_in_game, expected, "game {}", game_id);
+        }
+
+        cleanup(&players, &pool_key, &redislayer).await;
+    }
+}
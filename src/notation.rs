@@ -0,0 +1,180 @@
+use pleco::{BitMove, Board, PieceType};
+
+// Standard Algebraic Notation for a legal move in the given position, eg: Nbd7, exd6, e8=Q+, O-O#
+pub fn move_to_san(board: &Board, bit_move: BitMove) -> String {
+    let mut san = if bit_move.is_castle() {
+        if bit_move.is_king_castle() {"O-O".to_string()} else {"O-O-O".to_string()}
+    } else {
+        let piece = board.moved_piece(bit_move).type_of();
+        let src = bit_move.get_src().to_string();
+        let dest = bit_move.get_dest().to_string();
+        let mut san = String::new();
+
+        if piece == PieceType::P {
+            if bit_move.is_capture() {
+                san.push_str(&src[..1]);
+                san.push('x');
+            }
+            san.push_str(&dest);
+            if bit_move.is_promo() {
+                san.push('=');
+                san.push(bit_move.promo_piece().char_upper());
+            }
+        } else {
+            san.push(piece.char_upper());
+            san.push_str(&disambiguation(board, bit_move, piece));
+            if bit_move.is_capture() {
+                san.push('x');
+            }
+            san.push_str(&dest);
+        }
+        san
+    };
+
+    let mut board_after = board.shallow_clone();
+    board_after.apply_move(bit_move);
+    if board_after.checkmate() {
+        san.push('#');
+    } else if board_after.in_check() {
+        san.push('+');
+    }
+    san
+}
+
+// Long algebraic notation used by UCI, with the king's destination for castling, eg: e1g1, e7e8q
+pub fn move_to_uci(bit_move: BitMove) -> String {
+    // pleco stores castling as the king capturing its own rook, stringify() converts it back to the king's square
+    bit_move.stringify()
+}
+
+// The file, rank or full square needed when another piece of the same type can reach the same square
+fn disambiguation(board: &Board, bit_move: BitMove, piece: PieceType) -> String {
+    let src = bit_move.get_src().to_string();
+
+    let rivals: Vec<String> = board.generate_moves().iter()
+        .filter(|other| {
+            other.get_dest() == bit_move.get_dest()
+                && other.get_src() != bit_move.get_src()
+                && !other.is_castle()
+                && board.moved_piece(**other).type_of() == piece
+        })
+        .map(|other| other.get_src().to_string())
+        .collect();
+
+    if rivals.is_empty() {
+        String::new()
+    } else if rivals.iter().all(|rival| rival[..1] != src[..1]) {
+        src[..1].to_string()
+    } else if rivals.iter().all(|rival| rival[1..] != src[1..]) {
+        src[1..].to_string()
+    } else {
+        src
+    }
+}
+
+// The legal move written in UCI, eg: e2e4, e7e8q, e1g1 for castling
+pub fn parse_uci(board: &Board, uci: &str) -> Option<BitMove> {
+    let uci = uci.trim().to_lowercase();
+    board.generate_moves().iter().copied().find(|legal| move_to_uci(*legal) == uci)
+}
+
+// The legal move written in SAN, check / mate markers and annotations are optional, eg: Nxf3, Nxf3+, e8=Q, O-O
+pub fn parse_san(board: &Board, san: &str) -> Option<BitMove> {
+    let san = normalise_san(san);
+    board.generate_moves().iter().copied().find(|legal| normalise_san(&move_to_san(board, *legal)) == san)
+}
+
+// Whether the text is shaped like a UCI move, to tell a malformed move apart from an illegal one
+pub fn is_uci(uci: &str) -> bool {
+    let uci = uci.trim().as_bytes();
+    let is_square = |file: u8, rank: u8| (b'a'..=b'h').contains(&file) && (b'1'..=b'8').contains(&rank);
+    match uci.len() {
+        4 => is_square(uci[0], uci[1]) && is_square(uci[2], uci[3]),
+        5 => is_square(uci[0], uci[1]) && is_square(uci[2], uci[3]) && b"nbrq".contains(&uci[4].to_ascii_lowercase()),
+        _ => false,
+    }
+}
+
+fn normalise_san(san: &str) -> String {
+    san.trim()
+        .replace('0', "O") // castling written with zeros, ranks never contain a 0
+        .replace("e.p.", "")
+        .chars()
+        .filter(|c| !matches!(c, '+' | '#' | '!' | '?' | '=' | ' '))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn san(fen: &str, uci: &str) -> String {
+        let board = Board::from_fen(fen).unwrap();
+        let bit_move = parse_uci(&board, uci).expect("move should be legal");
+        move_to_san(&board, bit_move)
+    }
+
+    #[test]
+    fn san_marks_check_and_mate() {
+        assert_eq!(san("4k3/8/8/8/8/8/8/R3K3 w - - 0 1", "a1a8"), "Ra8+");
+        assert_eq!(san("r1bqkb1r/pppp1ppp/2n2n2/4p2Q/2B1P3/8/PPPP1PPP/RNB1K1NR w KQkq - 4 4", "h5f7"), "Qxf7#");
+    }
+
+    #[test]
+    fn san_disambiguates_by_file_rank_then_square() {
+        assert_eq!(san("4k3/8/8/8/8/5N2/8/1N2K3 w - - 0 1", "b1d2"), "Nbd2");
+        assert_eq!(san("4k3/8/8/R7/8/8/8/R3K3 w - - 0 1", "a1a3"), "R1a3");
+        assert_eq!(san("4k3/8/8/8/8/Q7/8/Q1Q1K3 w - - 0 1", "a1b2"), "Qa1b2");
+    }
+
+    #[test]
+    fn san_for_castling_pawn_captures_and_promotion() {
+        assert_eq!(san("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1g1"), "O-O");
+        assert_eq!(san("r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1", "e8c8"), "O-O-O");
+        assert_eq!(san("4k3/8/8/3p4/4P3/8/8/4K3 w - - 0 1", "e4d5"), "exd5");
+        assert_eq!(san("8/4P3/8/8/8/8/8/k3K3 w - - 0 1", "e7e8q"), "e8=Q");
+    }
+
+    #[test]
+    fn parse_uci_finds_the_legal_move() {
+        let board = Board::from_fen("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1").unwrap();
+        let castle = parse_uci(&board, "e1g1").unwrap();
+        assert!(castle.is_king_castle());
+        assert!(parse_uci(&board, "e1c1").unwrap().is_queen_castle());
+        assert_eq!(parse_uci(&board, " A1A8 ").map(move_to_uci).as_deref(), Some("a1a8"));
+        assert!(parse_uci(&board, "e1e3").is_none());
+
+        let board = Board::from_fen("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 2").unwrap();
+        assert!(parse_uci(&board, "e5d6").unwrap().is_en_passant());
+
+        let board = Board::from_fen("8/4P3/8/8/8/8/8/k3K3 w - - 0 1").unwrap();
+        let promotion = parse_uci(&board, "e7e8n").unwrap();
+        assert_eq!(promotion.promo_piece(), PieceType::N);
+        assert!(parse_uci(&board, "e7e8").is_none());
+    }
+
+    #[test]
+    fn parse_san_ignores_check_marks_and_annotations() {
+        let board = Board::from_fen("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1").unwrap();
+        assert!(parse_san(&board, "O-O").unwrap().is_king_castle());
+        assert!(parse_san(&board, "0-0-0").unwrap().is_queen_castle());
+        assert_eq!(parse_san(&board, "Rxa8+").map(move_to_uci).as_deref(), Some("a1a8"));
+        assert!(parse_san(&board, "Ra9").is_none());
+
+        let board = Board::from_fen("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 2").unwrap();
+        assert!(parse_san(&board, "exd6 e.p.").unwrap().is_en_passant());
+
+        let board = Board::from_fen("8/4P3/8/8/8/8/8/k3K3 w - - 0 1").unwrap();
+        assert_eq!(parse_san(&board, "e8=Q!").unwrap().promo_piece(), PieceType::Q);
+        assert_eq!(parse_san(&board, "e8R").unwrap().promo_piece(), PieceType::R);
+    }
+
+    #[test]
+    fn normalise_san_strips_everything_but_the_move() {
+        assert_eq!(normalise_san("0-0-0"), "O-O-O");
+        assert_eq!(normalise_san(" e8=Q+! "), "e8Q");
+        assert_eq!(normalise_san("exd6 e.p."), "exd6");
+        assert_eq!(normalise_san("Qxf7#"), "Qxf7");
+        assert_eq!(normalise_san("Nbd7?!"), "Nbd7");
+    }
+}
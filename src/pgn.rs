@@ -0,0 +1,143 @@
+use chrono::DateTime;
+use crate::gameserver::{Game, GameResult, Termination};
+
+const PGN_LINE_WIDTH: usize = 80;
+
+// Exports a game as PGN, with the Seven Tag Roster followed by the time control and termination tags
+pub fn format_pgn(game: &Game) -> String {
+    let result = pgn_result(game.result);
+    let date = DateTime::from_timestamp(game.game_created, 0)
+        .map(|created| created.format("%Y.%m.%d").to_string())
+        .unwrap_or_else(|| "????.??.??".to_string());
+
+    let tags = [
+        ("Event", format!("{} {} game", if game.rated {"Rated"} else {"Casual"}, game.variant.as_str())),
+        ("Site", "Radial Chess".to_string()),
+        ("Date", date),
+        ("Round", "-".to_string()),
+        ("White", game.player_white.to_string()),
+        ("Black", game.player_black.to_string()),
+        ("Result", result.to_string()),
+        ("TimeControl", format!("{}+{}", game.time_control.initial_secs, game.time_control.increment_secs)),
+        ("Termination", pgn_termination(game.termination).to_string()),
+    ];
+
+    let mut pgn = String::new();
+    for (name, value) in tags {
+        pgn.push_str(&format!("[{} \"{}\"]\n", name, value.replace('\\', "\\\\").replace('"', "\\\"")));
+    }
+    pgn.push('\n');
+
+    let mut tokens: Vec<String> = Vec::new();
+    for (ply, played_move) in game.moves.iter().enumerate() {
+        if ply % 2 == 0 {
+            tokens.push(format!("{}.", ply / 2 + 1));
+        }
+        tokens.push(played_move.san.clone());
+    }
+    tokens.push(result.to_string());
+
+    let mut line = String::new();
+    for token in tokens {
+        if !line.is_empty() && line.len() + 1 + token.len() > PGN_LINE_WIDTH {
+            pgn.push_str(&line);
+            pgn.push('\n');
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&token);
+    }
+    pgn.push_str(&line);
+    pgn.push_str("\n\n");
+    pgn
+}
+
+fn pgn_result(result: Option<GameResult>) -> &'static str {
+    match result {
+        Some(GameResult::WhiteWins) => "1-0",
+        Some(GameResult::BlackWins) => "0-1",
+        Some(GameResult::Draw) => "1/2-1/2",
+        None => "*",
+    }
+}
+
+fn pgn_termination(termination: Option<Termination>) -> &'static str {
+    match termination {
+        Some(Termination::Timeout) | Some(Termination::TimeoutVsInsufficientMaterial) => "time forfeit",
+        Some(Termination::Abandonment) => "abandoned",
+        Some(_) => "normal",
+        None => "unterminated",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameserver::{Clock, PlayedMove, TimeControl, Variant};
+
+    fn game(sans: &[&str], result: Option<GameResult>, termination: Option<Termination>) -> Game {
+        let time_control = TimeControl { initial_secs: 180, increment_secs: 2 };
+        Game {
+            game_id: 1,
+            player_white: 10,
+            player_black: 20,
+            game_created: 0,
+            game_initiated: 0,
+            game_ended: 0,
+            last_moved: (20, 0),
+            board_state: String::new(),
+            previous_move: None,
+            draw_offer: None,
+            result,
+            termination,
+            position_history: Vec::new(),
+            time_control,
+            rated: true,
+            variant: Variant::Standard,
+            clock: Clock::new(&time_control),
+            moves: sans.iter().map(|san| PlayedMove { san: san.to_string(), uci: String::new() }).collect(),
+            rating_change_white: None,
+            rating_change_black: None,
+            ply: sans.len() as u32,
+        }
+    }
+
+    #[test]
+    fn pgn_has_tag_roster_then_movetext() {
+        let pgn = format_pgn(&game(&["f3", "e5", "g4", "Qh4#"], Some(GameResult::BlackWins), Some(Termination::Checkmate)));
+        assert_eq!(pgn, concat!(
+            "[Event \"Rated standard game\"]\n",
+            "[Site \"Radial Chess\"]\n",
+            "[Date \"1970.01.01\"]\n",
+            "[Round \"-\"]\n",
+            "[White \"10\"]\n",
+            "[Black \"20\"]\n",
+            "[Result \"0-1\"]\n",
+            "[TimeControl \"180+2\"]\n",
+            "[Termination \"normal\"]\n",
+            "\n",
+            "1. f3 e5 2. g4 Qh4# 0-1\n",
+            "\n",
+        ));
+    }
+
+    #[test]
+    fn unfinished_game_is_marked_with_an_asterisk() {
+        let pgn = format_pgn(&game(&["e4"], None, None));
+        assert!(pgn.contains("[Result \"*\"]\n"));
+        assert!(pgn.contains("[Termination \"unterminated\"]\n"));
+        assert!(pgn.ends_with("\n1. e4 *\n\n"));
+    }
+
+    #[test]
+    fn movetext_wraps_at_line_width() {
+        let sans = ["Nf3", "Nf6", "Ng1", "Ng8"].repeat(10);
+        let pgn = format_pgn(&game(&sans, Some(GameResult::Draw), Some(Termination::FivefoldRepetition)));
+        let movetext = pgn.split("\n\n").nth(1).unwrap();
+        assert!(movetext.lines().count() > 1);
+        assert!(movetext.lines().all(|line| line.len() <= PGN_LINE_WIDTH));
+        assert!(movetext.ends_with("1/2-1/2"));
+    }
+}
@@ -0,0 +1,389 @@
+use axum::{extract::ws::Message, http::StatusCode, response::IntoResponse};
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::{
+    challenges::Challenge,
+    gameserver::{EventData, Move, PlayerColour},
+    matchmaking::{MatchmakingRequest, QueueStatus},
+    seeks::Seek,
+    utils::cors_response,
+};
+
+// WebSocket protocol, every message in either direction is {"event": "snake_case_name", "data": {...}}
+// The client names the newest version it speaks when it authenticates, the server answers in welcome with the
+// version it will use, or an error if there is none in common
+// v1 is the only version so far, so nothing past the handshake looks at the negotiated version. A v2 has to
+// carry it from handle_socket into the lobby and GameServer, wherever the messages it changes are built
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// Client -> server
+// struct variants are renamed one by one as schemars ignores rename_all_fields, and the schema on /protocol has
+// to have the same field names as the messages
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum ClientMessage {
+    // must be the first message on a new connection
+    #[serde(rename_all = "camelCase")]
+    Authenticate {
+        token: String,
+        protocol_version: u32,
+        // seq of the last game event the client saw, to catch up on what it missed when reconnecting
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_event_seq: Option<u64>,
+    },
+    Ping,
+    // data may be {} to queue for the default rated 10+0 standard game
+    MatchmakingJoin(MatchmakingRequest),
+    MatchmakingLeave,
+    GameMove(MoveRequest),
+    GameSurrender,
+    GameOfferDraw,
+    GameAcceptDraw,
+    GameDeclineDraw,
+    GameClaimDraw,
+    GameOfferRematch,
+    GameAcceptRematch,
+    GameReminder,
+}
+
+// A move in any one of the accepted notations, checked in the order uci, san, this_move
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub this_move: Option<Move>, // chess.js move
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uci: Option<String>, // eg: e7e8q
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub san: Option<String>, // eg: Nxf3+
+}
+
+// Server -> client
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum ServerMessage {
+    #[serde(rename_all = "camelCase")]
+    Welcome {
+        protocol_version: u32,
+        user_id: u32,
+    },
+    // the connection is closed after an error sent before a game or lobby has started, eg: failed authentication
+    Error {
+        message: String,
+    },
+    Pong,
+
+    // lobby, for players without a game
+    LobbyJoined(Option<QueueStatus>),
+    QueueStatus(QueueStatus),
+    MatchmakingError {
+        message: String,
+    },
+    MatchmakingLeft {
+        removed: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    MatchFound {
+        game_id: u32,
+    },
+    #[serde(rename_all = "camelCase")]
+    ChallengeNew {
+        challenge_id: String,
+        challenge: Option<Challenge>,
+    },
+    #[serde(rename_all = "camelCase")]
+    ChallengeAccepted {
+        challenge_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    ChallengeDeclined {
+        challenge_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    ChallengeCancelled {
+        challenge_id: String,
+    },
+
+    // seek feed
+    Seeks(Vec<Seek>),
+    SeekAdded(Seek),
+    #[serde(rename_all = "camelCase")]
+    SeekRemoved {
+        seek_id: String,
+    },
+
+    // game
+    #[serde(rename_all = "camelCase")]
+    GameInitiated {
+        player_colour: PlayerColour,
+    },
+    GameMove(EventData),
+    GameSurrender(EventData),
+    GameOfferDraw(EventData),
+    GameAcceptDraw(EventData),
+    GameDeclineDraw(EventData),
+    GameOver(EventData),
+    GameConnection(EventData),
+    GameReminder(EventData),
+    GameResume(EventData),
+    GameOfferRematch(EventData),
+    #[serde(rename_all = "camelCase")]
+    GameRematch {
+        game_id: u32,
+    },
+    // the game was called off, eg: the opponent never joined, the connection is closed after this
+    #[serde(rename_all = "camelCase")]
+    GameAborted {
+        game_id: u32,
+    },
+}
+
+impl ServerMessage {
+    pub fn to_ws(&self) -> Message {
+        Message::Text(serde_json::to_string(self).unwrap())
+    }
+}
+
+// Highest version both sides speak, None if the client is too old
+pub fn negotiate_version(client_version: u32) -> Option<u32> {
+    let version = client_version.min(PROTOCOL_VERSION);
+    if version < MIN_PROTOCOL_VERSION {
+        return None;
+    }
+    Some(version)
+}
+
+// JSON schema of both message enums, for type checking clients against
+pub async fn protocol_schema() -> impl IntoResponse {
+    cors_response(StatusCode::OK, json!({
+        "version": PROTOCOL_VERSION,
+        "minVersion": MIN_PROTOCOL_VERSION,
+        "client": schema_for!(ClientMessage),
+        "server": schema_for!(ServerMessage),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    // Just enough of JSON schema to check messages against what schemars publishes on /protocol
+    fn validate(schema: &Value, value: &Value, root: &Value) -> Result<(), String> {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let name = reference.trim_start_matches("#/definitions/");
+            return validate(&root["definitions"][name], value, root);
+        }
+        if let Some(all_of) = schema.get("allOf").and_then(Value::as_array) {
+            for sub_schema in all_of {
+                validate(sub_schema, value, root)?;
+            }
+        }
+        if let Some(any_of) = schema.get("anyOf").and_then(Value::as_array) {
+            if !any_of.iter().any(|sub_schema| validate(sub_schema, value, root).is_ok()) {
+                return Err(format!("{} matches nothing in anyOf", value));
+            }
+        }
+        if let Some(one_of) = schema.get("oneOf").and_then(Value::as_array) {
+            let matches = one_of.iter().filter(|sub_schema| validate(sub_schema, value, root).is_ok()).count();
+            if matches != 1 {
+                return Err(format!("{} matches {} schemas in oneOf", value, matches));
+            }
+        }
+        if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+            if !options.contains(value) {
+                return Err(format!("{} is not one of {:?}", value, options));
+            }
+        }
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                types => types.as_str().into_iter().collect(),
+            };
+            let is_type = |name: &str| match name {
+                "null" => value.is_null(),
+                "boolean" => value.is_boolean(),
+                "integer" => value.is_i64() || value.is_u64(),
+                "number" => value.is_number(),
+                "string" => value.is_string(),
+                "array" => value.is_array(),
+                "object" => value.is_object(),
+                _ => false,
+            };
+            if !types.iter().any(|name| is_type(name)) {
+                return Err(format!("{} is not of type {:?}", value, types));
+            }
+        }
+        if let (Some(minimum), Some(number)) = (schema.get("minimum").and_then(Value::as_f64), value.as_f64()) {
+            if number < minimum {
+                return Err(format!("{} is below {}", value, minimum));
+            }
+        }
+        if let Some(object) = value.as_object() {
+            for required in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+                if !object.contains_key(required.as_str().unwrap_or_default()) {
+                    return Err(format!("{} is missing {}", value, required));
+                }
+            }
+            if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+                for (key, field) in object {
+                    match properties.get(key) {
+                        Some(property) => validate(property, field, root)?,
+                        None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                            return Err(format!("{} has unexpected field {}", value, key));
+                        }
+                        None => {}
+                    }
+                }
+            }
+        }
+        if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+            for item in array {
+                validate(items, item, root)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn schema<T: JsonSchema>() -> Value {
+        serde_json::to_value(schema_for!(T)).unwrap()
+    }
+
+    const MOVE: &str = r#"{"from":"e7","to":"e8","flags":"np","captured":"r","promotion":"q"}"#;
+    const CLOCK: &str = r#"{"whiteMs":179000,"blackMs":180000,"turnStartedMs":1700000000000}"#;
+    const SETTINGS: &str = r#"{"timeControl":{"initialSecs":180,"incrementSecs":2},"rated":true,"variant":"standard"}"#;
+
+    fn event_data(status: &str) -> String {
+        format!(r#"{{"player":"white","thisMove":null,"status":"{}","seq":7}}"#, status)
+    }
+
+    fn client_messages() -> Vec<String> {
+        vec![
+            r#"{"event":"authenticate","data":{"token":"jwt","protocolVersion":1}}"#.to_string(),
+            r#"{"event":"authenticate","data":{"token":"jwt","protocolVersion":1,"lastEventSeq":12}}"#.to_string(),
+            r#"{"event":"ping"}"#.to_string(),
+            r#"{"event":"matchmaking_join","data":{}}"#.to_string(),
+            r#"{"event":"matchmaking_join","data":{"timeControl":"3+2","rated":false,"variant":"standard"}}"#.to_string(),
+            r#"{"event":"matchmaking_leave"}"#.to_string(),
+            format!(r#"{{"event":"game_move","data":{{"thisMove":{}}}}}"#, MOVE),
+            r#"{"event":"game_move","data":{"uci":"e7e8q"}}"#.to_string(),
+            r#"{"event":"game_move","data":{"san":"Nxf3+"}}"#.to_string(),
+            r#"{"event":"game_surrender"}"#.to_string(),
+            r#"{"event":"game_offer_draw"}"#.to_string(),
+            r#"{"event":"game_accept_draw"}"#.to_string(),
+            r#"{"event":"game_decline_draw"}"#.to_string(),
+            r#"{"event":"game_claim_draw"}"#.to_string(),
+            r#"{"event":"game_offer_rematch"}"#.to_string(),
+            r#"{"event":"game_accept_rematch"}"#.to_string(),
+            r#"{"event":"game_reminder"}"#.to_string(),
+        ]
+    }
+
+    fn server_messages() -> Vec<String> {
+        let queue_status = r#"{"pool":"matchmaking_pool:standard:3+2:rated","position":1,"playersWaiting":2,"waitingSecs":5}"#;
+        let challenge = format!(
+            r#"{{"challengeId":"abc","challenger":1,"opponent":null,"colour":"random","settings":{},"created":1700000000,"expires":1700003600}}"#,
+            SETTINGS,
+        );
+        let seek = format!(
+            r#"{{"seekId":"def","userId":1,"rating":1500.5,"provisional":true,"colour":"white","settings":{},"created":1700000000}}"#,
+            SETTINGS,
+        );
+        let game_state = format!(
+            r#"{{"gameId":3,"fen":"8/8/8/8/8/8/8/8 w - - 0 1","moves":[{{"san":"e4","uci":"e2e4"}}],"turn":"black","playerWhite":1,"playerBlack":2,"clock":{},"serverTimeMs":1700000001000,"drawOffer":null}}"#,
+            CLOCK,
+        );
+        let rating_change = r#"{"before":1500.0,"after":1512.0,"change":12.0,"provisional":false}"#;
+        vec![
+            r#"{"event":"welcome","data":{"protocolVersion":1,"userId":1}}"#.to_string(),
+            r#"{"event":"error","data":{"message":"Authentication failed"}}"#.to_string(),
+            r#"{"event":"pong"}"#.to_string(),
+            r#"{"event":"lobby_joined","data":null}"#.to_string(),
+            format!(r#"{{"event":"lobby_joined","data":{}}}"#, queue_status),
+            format!(r#"{{"event":"queue_status","data":{}}}"#, queue_status),
+            r#"{"event":"matchmaking_error","data":{"message":"Invalid time control"}}"#.to_string(),
+            r#"{"event":"matchmaking_left","data":{"removed":true}}"#.to_string(),
+            r#"{"event":"match_found","data":{"gameId":3}}"#.to_string(),
+            format!(r#"{{"event":"challenge_new","data":{{"challengeId":"abc","challenge":{}}}}}"#, challenge),
+            r#"{"event":"challenge_new","data":{"challengeId":"abc","challenge":null}}"#.to_string(),
+            r#"{"event":"challenge_accepted","data":{"challengeId":"abc"}}"#.to_string(),
+            r#"{"event":"challenge_declined","data":{"challengeId":"abc"}}"#.to_string(),
+            r#"{"event":"challenge_cancelled","data":{"challengeId":"abc"}}"#.to_string(),
+            format!(r#"{{"event":"seeks","data":[{}]}}"#, seek),
+            format!(r#"{{"event":"seek_added","data":{}}}"#, seek),
+            r#"{"event":"seek_removed","data":{"seekId":"def"}}"#.to_string(),
+            r#"{"event":"game_initiated","data":{"playerColour":"black"}}"#.to_string(),
+            format!(
+                r#"{{"event":"game_move","data":{{"player":"white","thisMove":{},"status":"UpdateNewMove","clock":{},"fen":"8/8/8/8/8/8/8/8 b - - 0 1","seq":4}}}}"#,
+                MOVE, CLOCK,
+            ),
+            r#"{"event":"game_move","data":{"player":"black","thisMove":null,"status":"EchoFailure","reason":"stalePosition"}}"#.to_string(),
+            format!(r#"{{"event":"game_surrender","data":{}}}"#, event_data("OpponentSurrender")),
+            format!(r#"{{"event":"game_offer_draw","data":{}}}"#, event_data("OpponentOfferedDraw")),
+            format!(r#"{{"event":"game_accept_draw","data":{}}}"#, event_data("DrawAgreed")),
+            format!(r#"{{"event":"game_decline_draw","data":{}}}"#, event_data("OpponentDeclinedDraw")),
+            format!(
+                r#"{{"event":"game_over","data":{{"player":"white","thisMove":null,"status":"GameOver","result":"1/2-1/2","termination":"threefoldRepetition","ratingChange":{}}}}}"#,
+                rating_change,
+            ),
+            format!(r#"{{"event":"game_connection","data":{}}}"#, event_data("OpponentDisconnected")),
+            format!(r#"{{"event":"game_reminder","data":{}}}"#, event_data("Reminder")),
+            format!(
+                r#"{{"event":"game_resume","data":{{"player":"black","thisMove":null,"status":"Resumed","state":{}}}}}"#,
+                game_state,
+            ),
+            format!(r#"{{"event":"game_offer_rematch","data":{}}}"#, event_data("OpponentOfferedRematch")),
+            r#"{"event":"game_rematch","data":{"gameId":4}}"#.to_string(),
+            r#"{"event":"game_aborted","data":{"gameId":3}}"#.to_string(),
+        ]
+    }
+
+    #[test]
+    fn client_messages_round_trip_and_match_the_schema() {
+        let schema = schema::<ClientMessage>();
+        for json in client_messages() {
+            let expected: Value = serde_json::from_str(&json).unwrap();
+            validate(&schema, &expected, &schema).unwrap_or_else(|e| panic!("{}: {}", json, e));
+            let message: ClientMessage = serde_json::from_str(&json).unwrap_or_else(|e| panic!("{}: {}", json, e));
+            assert_eq!(serde_json::to_value(&message).unwrap(), expected, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn server_messages_round_trip_and_match_the_schema() {
+        let schema = schema::<ServerMessage>();
+        for json in server_messages() {
+            let expected: Value = serde_json::from_str(&json).unwrap();
+            validate(&schema, &expected, &schema).unwrap_or_else(|e| panic!("{}: {}", json, e));
+            let message: ServerMessage = serde_json::from_str(&json).unwrap_or_else(|e| panic!("{}: {}", json, e));
+            assert_eq!(serde_json::to_value(&message).unwrap(), expected, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn matchmaking_join_still_takes_a_snake_case_time_control() {
+        let json = r#"{"event":"matchmaking_join","data":{"time_control":"3+2"}}"#;
+        let message: ClientMessage = serde_json::from_str(json).unwrap();
+        let expected: Value = serde_json::from_str(r#"{"event":"matchmaking_join","data":{"timeControl":"3+2"}}"#).unwrap();
+        assert_eq!(serde_json::to_value(&message).unwrap(), expected);
+    }
+
+    #[test]
+    fn schema_rejects_unknown_events_and_missing_data() {
+        let schema = schema::<ClientMessage>();
+        for json in [r#"{"event":"pinggg"}"#, r#"{"event":"game_move"}"#, r#"{"event":"authenticate","data":{"token":"jwt"}}"#] {
+            let value: Value = serde_json::from_str(json).unwrap();
+            assert!(validate(&schema, &value, &schema).is_err(), "{}", json);
+            assert!(serde_json::from_str::<ClientMessage>(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn negotiates_the_highest_common_version() {
+        assert_eq!(negotiate_version(PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(PROTOCOL_VERSION + 5), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION - 1), None);
+    }
+}
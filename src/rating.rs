@@ -0,0 +1,165 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+// Glicko-2 rating system (http://www.glicko.net/glicko/glicko2.pdf), with each game treated as its own rating period
+
+pub const DEFAULT_RATING: f64 = 1500.0;
+pub const DEFAULT_DEVIATION: f64 = 350.0;
+pub const DEFAULT_VOLATILITY: f64 = 0.06;
+
+const MIN_DEVIATION: f64 = 45.0;
+const MAX_DEVIATION: f64 = 350.0;
+// ratings are shown as provisional until the deviation has settled below this
+const PROVISIONAL_DEVIATION: f64 = 110.0;
+
+const GLICKO2_SCALE: f64 = 173.7178;
+const TAU: f64 = 0.5; // constrains how quickly volatility can change
+const CONVERGENCE_TOLERANCE: f64 = 0.000001;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rating {
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Rating { rating: DEFAULT_RATING, deviation: DEFAULT_DEVIATION, volatility: DEFAULT_VOLATILITY }
+    }
+}
+
+impl Rating {
+    pub fn is_provisional(&self) -> bool {
+        self.deviation > PROVISIONAL_DEVIATION
+    }
+
+    // New rating after a single game against the opponent, score is 1.0 for a win, 0.5 for a draw and 0.0 for a loss
+    pub fn updated(&self, opponent: &Rating, score: f64) -> Rating {
+        self.updated_for_period(&[(*opponent, score)])
+    }
+
+    // New rating after a rating period of games, each given as (opponent, score)
+    pub fn updated_for_period(&self, games: &[(Rating, f64)]) -> Rating {
+        let mu = (self.rating - DEFAULT_RATING) / GLICKO2_SCALE;
+        let phi = self.deviation / GLICKO2_SCALE;
+
+        // g(phi_j) and E(mu, mu_j, phi_j) for each opponent
+        let outcomes: Vec<(f64, f64, f64)> = games.iter().map(|(opponent, score)| {
+            let opponent_mu = (opponent.rating - DEFAULT_RATING) / GLICKO2_SCALE;
+            let opponent_phi = opponent.deviation / GLICKO2_SCALE;
+            let g = 1.0 / (1.0 + 3.0 * opponent_phi.powi(2) / PI.powi(2)).sqrt();
+            let expected = 1.0 / (1.0 + (-g * (mu - opponent_mu)).exp());
+            (g, expected, *score)
+        }).collect();
+
+        let variance = 1.0 / outcomes.iter().map(|(g, expected, _)| g.powi(2) * expected * (1.0 - expected)).sum::<f64>();
+        let improvement: f64 = outcomes.iter().map(|(g, expected, score)| g * (score - expected)).sum();
+        let delta = variance * improvement;
+
+        let volatility = new_volatility(phi, self.volatility, variance, delta);
+
+        let phi_star = (phi.powi(2) + volatility.powi(2)).sqrt();
+        let new_phi = 1.0 / (1.0 / phi_star.powi(2) + 1.0 / variance).sqrt();
+        let new_mu = mu + new_phi.powi(2) * improvement;
+
+        Rating {
+            rating: GLICKO2_SCALE * new_mu + DEFAULT_RATING,
+            deviation: (GLICKO2_SCALE * new_phi).clamp(MIN_DEVIATION, MAX_DEVIATION),
+            volatility,
+        }
+    }
+}
+
+// Result of a rated game for one player, sent to clients in the game over event
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RatingChange {
+    pub before: f64,
+    pub after: f64,
+    pub change: f64,
+    pub provisional: bool,
+}
+
+impl RatingChange {
+    pub fn new(before: &Rating, after: &Rating) -> Self {
+        RatingChange {
+            before: before.rating.round(),
+            after: after.rating.round(),
+            change: after.rating.round() - before.rating.round(),
+            provisional: after.is_provisional(),
+        }
+    }
+}
+
+// Step 5 of the Glicko-2 paper, solved with the Illinois algorithm
+fn new_volatility(phi: f64, volatility: f64, variance: f64, delta: f64) -> f64 {
+    let a = volatility.powi(2).ln();
+    let f = |x: f64| {
+        let ex = x.exp();
+        ex * (delta.powi(2) - phi.powi(2) - variance - ex) / (2.0 * (phi.powi(2) + variance + ex).powi(2))
+            - (x - a) / TAU.powi(2)
+    };
+
+    let mut lower = a;
+    let mut upper = if delta.powi(2) > phi.powi(2) + variance {
+        (delta.powi(2) - phi.powi(2) - variance).ln()
+    } else {
+        let mut k = 1.0;
+        while f(a - k * TAU) < 0.0 {
+            k += 1.0;
+        }
+        a - k * TAU
+    };
+
+    let mut f_lower = f(lower);
+    let mut f_upper = f(upper);
+    while (upper - lower).abs() > CONVERGENCE_TOLERANCE {
+        let candidate = lower + (lower - upper) * f_lower / (f_upper - f_lower);
+        let f_candidate = f(candidate);
+        if f_candidate * f_upper <= 0.0 {
+            lower = upper;
+            f_lower = f_upper;
+        } else {
+            f_lower /= 2.0;
+        }
+        upper = candidate;
+        f_upper = f_candidate;
+    }
+
+    (lower / 2.0).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(rating: f64, deviation: f64) -> Rating {
+        Rating { rating, deviation, volatility: DEFAULT_VOLATILITY }
+    }
+
+    #[test]
+    fn matches_the_worked_example_in_the_glicko2_paper() {
+        let player = rating(1500.0, 200.0);
+        let games = [
+            (rating(1400.0, 30.0), 1.0),
+            (rating(1550.0, 100.0), 0.0),
+            (rating(1700.0, 300.0), 0.0),
+        ];
+
+        let updated = player.updated_for_period(&games);
+
+        assert!((updated.rating - 1464.06).abs() < 0.01, "rating {}", updated.rating);
+        assert!((updated.deviation - 151.52).abs() < 0.01, "deviation {}", updated.deviation);
+        assert!((updated.volatility - 0.05999).abs() < 0.00001, "volatility {}", updated.volatility);
+    }
+
+    #[test]
+    fn single_game_is_a_period_of_one() {
+        let player = rating(1500.0, 200.0);
+        let opponent = rating(1400.0, 30.0);
+        assert_eq!(player.updated(&opponent, 1.0), player.updated_for_period(&[(opponent, 1.0)]));
+    }
+}
@@ -1,6 +1,7 @@
 use log::info;
 use redis::aio::MultiplexedConnection;
 use redis::AsyncCommands;
+use redis::streams::{StreamMaxlen, StreamReadOptions, StreamReadReply};
 use redis::Client;
 use redis::Connection;
 use redis::ToRedisArgs;
@@ -9,11 +10,58 @@ use std::env;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use dotenv::dotenv;
-use crate::gameserver::Game;
+use crate::events::{GameEvent, GameUpdate};
+use crate::gameserver::{Clock, Game, TimeControl};
+use crate::rating::Rating;
 use redis::RedisResult;
 use std::collections::HashMap;
 use redis_async::client::pubsub::pubsub_connect;
 
+// Removes both players from the pool only if both are still in it, returns 1 if they were claimed
+const CLAIM_PAIR_SCRIPT: &str = r#"
+if redis.call('ZSCORE', KEYS[1], ARGV[1]) and redis.call('ZSCORE', KEYS[1], ARGV[2]) then
+    redis.call('ZREM', KEYS[1], ARGV[1], ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+// Drops a matchmaking pool (ARGV[1]) from the pool list (KEYS[1]) only if nobody is waiting in it,
+// joining adds the player before listing the pool so a pool in use is never dropped for good
+const REMOVE_EMPTY_POOL_SCRIPT: &str = r#"
+if redis.call('ZCARD', ARGV[1]) == 0 then
+    return redis.call('HDEL', KEYS[1], ARGV[1])
+end
+return 0
+"#;
+
+// Numbers the event and appends it to the game's stream in one step, so concurrent publishers can't append out of order
+const APPEND_GAME_EVENT_SCRIPT: &str = r#"
+local seq = redis.call('HINCRBY', KEYS[1], 'event_seq', 1)
+redis.call('XADD', KEYS[2], seq .. '-0', 'event', ARGV[1])
+return seq
+"#;
+
+// Writes a move's fields and bumps the ply only if the game (ARGV[1]) is still in active_games (KEYS[2]) and at the ply
+// the move was checked against (ARGV[2]), the rest of ARGV is field / value pairs.
+// Returns 0 if another move got there first or the game has been finished, which takes it out of active_games
+const APPLY_MOVE_SCRIPT: &str = r#"
+if not redis.call('ZSCORE', KEYS[2], ARGV[1]) then
+    return 0
+end
+if tonumber(redis.call('HGET', KEYS[1], 'ply') or '0') ~= tonumber(ARGV[2]) then
+    return 0
+end
+for i = 3, #ARGV, 2 do
+    redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+redis.call('HINCRBY', KEYS[1], 'ply', 1)
+return 1
+"#;
+
+// most entries read from a game's stream in one go
+const GAME_EVENTS_READ_COUNT: usize = 100;
+
 #[derive(Clone)]
 pub struct RedisLayer {
     connection: Arc<Mutex<MultiplexedConnection>>,
@@ -42,7 +90,7 @@ impl RedisLayer {
     }
 
     //redis get (key, value) command
-    pub async fn get(&self, key: &str) -> Result<String, redis::RedisError> {
+    pub async fn get(&self, key: &str) -> Result<Option<String>, redis::RedisError> {
         let mut con = self.connection.lock().await;
         con.get(key).await
     }
@@ -52,30 +100,118 @@ impl RedisLayer {
         con.del(key).await
     }
 
+    pub async fn expire(&self, key: &str, seconds: i64) -> Result<(), redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.expire(key, seconds).await
+    }
+
+    pub async fn set_ex(&self, key: &str, value: &str, seconds: u64) -> Result<(), redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.set_ex(key, value, seconds).await
+    }
+
+    // reads and deletes a key in one step, so only one caller can ever take it
+    pub async fn get_del(&self, key: &str) -> Result<Option<String>, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.get_del(key).await
+    }
+
+    pub async fn sadd(&self, key: &str, member: &str) -> Result<(), redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.sadd(key, member).await
+    }
+
+    pub async fn srem(&self, key: &str, member: &str) -> Result<(), redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.srem(key, member).await
+    }
+
+    pub async fn smembers(&self, key: &str) -> Result<Vec<String>, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.smembers(key).await
+    }
+
     pub async fn zscore(&self, key: &str, member: &str) -> Result<Option<f64>, redis::RedisError> {
         let mut con = self.connection.lock().await;
         con.zscore(key, member).await
     }
 
+    pub async fn zrank(&self, key: &str, member: &str) -> Result<Option<u64>, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.zrank(key, member).await
+    }
+
     pub async fn zadd(&self, key: &str, member: &str, score: f64) -> Result<(), redis::RedisError> {
         let mut con = self.connection.lock().await;
         con.zadd(key, member, score).await
     }
 
-    pub async fn zrem(&self, key: &str, member: &str) -> Result<(), redis::RedisError> {
+    pub async fn zrem(&self, key: &str, member: &str) -> Result<usize, redis::RedisError> {
         let mut con = self.connection.lock().await;
         con.zrem(key, member).await
     }    
 
+    pub async fn zrange(&self, key: &str, start: isize, stop: isize) -> Result<Vec<String>, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.zrange(key, start, stop).await
+    }
+
+    pub async fn zrangebyscore(&self, key: &str, min: f64, max: f64) -> Result<Vec<String>, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.zrangebyscore(key, min, max).await
+    }
+
+    pub async fn zrange_withscores(&self, key: &str, start: isize, stop: isize) -> Result<Vec<(String, f64)>, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.zrange_withscores(key, start, stop).await
+    }
+
+    pub async fn claim_pair(&self, key: &str, player1: u32, player2: u32) -> Result<bool, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        let claimed: i32 = redis::Script::new(CLAIM_PAIR_SCRIPT)
+            .key(key)
+            .arg(player1)
+            .arg(player2)
+            .invoke_async(&mut *con)
+            .await?;
+        Ok(claimed == 1)
+    }
+
+    pub async fn remove_empty_pool(&self, pools_key: &str, pool_key: &str) -> Result<bool, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        let removed: i32 = redis::Script::new(REMOVE_EMPTY_POOL_SCRIPT)
+            .key(pools_key)
+            .arg(pool_key)
+            .invoke_async(&mut *con)
+            .await?;
+        Ok(removed == 1)
+    }
+
     pub async fn zcard(&self, key: &str) -> Result<u64, redis::RedisError> {
         let mut con = self.connection.lock().await;
         con.zcard(key).await
     }
 
-    pub async fn zpopmin(&self, key: &str, count: isize) -> Result<Vec<(String, f64)>, redis::RedisError> {
+    pub async fn lrange(&self, key: &str, start: isize, stop: isize) -> Result<Vec<String>, redis::RedisError> {
         let mut con = self.connection.lock().await;
-        let result: Vec<(String, f64)>  = con.zpopmin(key, count).await?;
-        Ok(result)
+        con.lrange(key, start, stop).await
+    }
+
+    pub async fn ltrim(&self, key: &str, start: isize, stop: isize) -> Result<(), redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.ltrim(key, start, stop).await
+    }
+
+    pub async fn lpush(&self, key: &str, value: &str) -> Result<(), redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.lpush(key, value).await
+    }
+
+    // blocks for up to timeout_secs waiting for a value, returns None on timeout
+    pub async fn blpop(&self, key: &str, timeout_secs: f64) -> Result<Option<String>, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        let result: Option<(String, String)> = con.blpop(key, timeout_secs).await?;
+        Ok(result.map(|(_key, value)| value))
     }
 
     pub async fn incr(&self, key: &str) -> Result<i64, redis::RedisError> {
@@ -89,26 +225,7 @@ impl RedisLayer {
         let game_data: RedisResult<HashMap<String, String>> = con.hgetall(&format!("game:{}", game_id)).await;
 
         match game_data {
-            Ok(data) => {
-                let game = Game {
-                    game_id: data.get("game_id")?.parse().ok().unwrap(),
-                    player_white: data.get("player_white").unwrap().parse().ok().unwrap(),
-                    player_black: data.get("player_black").unwrap().parse().ok().unwrap(),
-                    game_created: data.get("game_created").unwrap().parse().ok().unwrap(),
-                    game_initiated: data.get("game_initiated").unwrap().parse().ok().unwrap(),
-                    last_moved: {
-                        let last_moved_str = data.get("last_moved").unwrap();
-                        let last_moved_tuple: (u32, i64) = serde_json::from_str(last_moved_str).ok().unwrap();
-                        last_moved_tuple
-                    },
-                    board_state: data.get("board_state").unwrap().clone(),
-                    previous_move: {
-                        let previous_move_str = data.get("previous_move").unwrap();
-                        serde_json::from_str(previous_move_str).ok().unwrap()
-                    },
-                };
-                Some(game)
-            },
+            Ok(data) => parse_game(&data),
             Err(_) => {
                 None
             }
@@ -120,6 +237,11 @@ impl RedisLayer {
         con.hget(key, field).await.expect("Failed getting value")
     }
 
+    pub async fn hgetall(&self, key: &str) -> Result<HashMap<String, String>, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        con.hgetall(key).await
+    }
+
     pub async fn hset(&self, key: &str, field: &str, value: &str) -> Result<(), redis::RedisError> {
         let mut con = self.connection.lock().await;
         con.hset(key, field, value).await
@@ -133,7 +255,7 @@ impl RedisLayer {
     pub async fn hset_multiple<T: ToRedisArgs + Send + Sync>(&self, key: &str, fields: &[(String, T)]) -> Result<(), redis::RedisError> {
         let mut con = self.connection.lock().await;
         for (field, value) in fields {
-            con.hset(key, field, value).await?;
+            con.hset::<_, _, _, ()>(key, field, value).await?;
         }
         Ok(())
     }
@@ -148,23 +270,304 @@ impl RedisLayer {
             ("player_black".to_string(), game.player_black.to_string()),
             ("game_created".to_string(), game.game_created.to_string()),
             ("game_initiated".to_string(), game.game_initiated.to_string()),
+            ("game_ended".to_string(), game.game_ended.to_string()),
             ("last_moved".to_string(), serde_json::to_string(&game.last_moved).unwrap()), // Using Debug trait for tuple
             ("board_state".to_string(), game.board_state.clone()),
             ("previous_move".to_string(), serde_json::to_string(&game.previous_move).unwrap()), // Using Debug for Option
+            ("draw_offer".to_string(), serde_json::to_string(&game.draw_offer).unwrap()),
+            ("result".to_string(), serde_json::to_string(&game.result).unwrap()),
+            ("termination".to_string(), serde_json::to_string(&game.termination).unwrap()),
+            ("position_history".to_string(), serde_json::to_string(&game.position_history).unwrap()),
+            ("time_control".to_string(), serde_json::to_string(&game.time_control).unwrap()),
+            ("rated".to_string(), game.rated.to_string()),
+            ("variant".to_string(), serde_json::to_string(&game.variant).unwrap()),
+            ("clock".to_string(), serde_json::to_string(&game.clock).unwrap()),
+            ("moves".to_string(), serde_json::to_string(&game.moves).unwrap()),
+            ("rating_change_white".to_string(), serde_json::to_string(&game.rating_change_white).unwrap()),
+            ("rating_change_black".to_string(), serde_json::to_string(&game.rating_change_black).unwrap()),
+            ("ply".to_string(), game.ply.to_string()),
         ];
     
         con.hset_multiple(&format!("game:{}", game.game_id), &fields).await
     }
 
+    // Applies a move to the game if nothing else has been played since it was read at ply, so each ply is written exactly once.
+    // returns false if the game had already moved on or finished, in which case nothing is written
+    pub async fn apply_move(&self, game_id: u32, ply: u32, fields: &[(String, String)]) -> Result<bool, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        let script = redis::Script::new(APPLY_MOVE_SCRIPT);
+        let mut invocation = script.key(format!("game:{}", game_id));
+        invocation.key("active_games").arg(game_id).arg(ply);
+        for (field, value) in fields {
+            invocation.arg(field).arg(value);
+        }
+        let applied: i32 = invocation.invoke_async(&mut *con).await?;
+        Ok(applied == 1)
+    }
+
+    pub async fn get_rating(&self, user_id: u32) -> Result<Rating, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        let stats: HashMap<String, String> = con.hgetall(format!("player_stats:{}", user_id)).await?;
+        Ok(rating_from_stats(&stats))
+    }
+
+    // Applies a rated game result to both players in one transaction, retrying if either rating changes mid-update
+    // returns the (before, after) ratings for white and black
+    pub async fn update_ratings(&self, player_white: u32, player_black: u32, white_score: f64) -> Result<((Rating, Rating), (Rating, Rating)), redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        let white_key = format!("player_stats:{}", player_white);
+        let black_key = format!("player_stats:{}", player_black);
+
+        loop {
+            match try_update_ratings(&mut con, &white_key, &black_key, white_score).await {
+                Ok(Some(ratings)) => return Ok(ratings),
+                Ok(None) => info!("ratings for {} and {} changed during update, retrying", player_white, player_black),
+                Err(e) => {
+                    // the connection is shared, so it mustn't be left watching the keys
+                    let _ = redis::cmd("UNWATCH").query_async::<()>(&mut *con).await;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
     pub async fn publish(&self, channel: &str, message: &str) -> Result<(), redis::RedisError> {
         let mut con = self.connection.lock().await;
         con.publish(channel, message).await
     }
 
+    // Appends the event to game_events:{game_id} under the game's next sequence number, which is returned
+    pub async fn publish_game_event(&self, game_id: u32, event: GameEvent) -> Result<u64, redis::RedisError> {
+        let event = serde_json::to_string(&event)
+            .map_err(|e| redis::RedisError::from((redis::ErrorKind::TypeError, "Failed to serialise game event", e.to_string())))?;
+        let mut con = self.connection.lock().await;
+        redis::Script::new(APPEND_GAME_EVENT_SCRIPT)
+            .key(format!("game:{}", game_id))
+            .key(format!("game_events:{}", game_id))
+            .arg(event)
+            .invoke_async(&mut *con)
+            .await
+    }
+
+    // The game along with the sequence number of the last event it is known to include, for a reader to carry on from.
+    // The seq is read before the game so no event after it can be missed, at worst one is in both and sent twice
+    pub async fn get_game_snapshot(&self, game_id: u32) -> Option<(u64, Game)> {
+        let seq = self.hget(&format!("game:{}", game_id), "event_seq").await
+            .and_then(|seq| seq.parse().ok())
+            .unwrap_or(0);
+        Some((seq, self.get_game(game_id).await?))
+    }
+
+    // Events after after_seq, waiting up to block_ms for one to arrive if there are none yet.
+    // This blocks the connection while it waits, so give readers a RedisLayer of their own
+    pub async fn read_game_events(&self, game_id: u32, after_seq: u64, block_ms: usize) -> Result<Vec<GameUpdate>, redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        let options = StreamReadOptions::default().block(block_ms).count(GAME_EVENTS_READ_COUNT);
+        let reply: Option<StreamReadReply> = con.xread_options(&[format!("game_events:{}", game_id)], &[format!("{}-0", after_seq)], &options).await?;
+
+        let mut updates = Vec::new();
+        for entry in reply.into_iter().flat_map(|reply| reply.keys).flat_map(|key| key.ids) {
+            let seq = match entry.id.split('-').next().and_then(|seq| seq.parse::<u64>().ok()) {
+                Some(seq) => seq,
+                None => continue,
+            };
+            let event = match entry.get::<String>("event") {
+                Some(event) => serde_json::from_str(&event).map_err(|e| format!("{}: {}", e, event)),
+                None => Err("entry has no event field".to_string()),
+            };
+            updates.push(GameUpdate { seq, event });
+        }
+        Ok(updates)
+    }
+
+    // Drops all but the last keep events of an archived game, the stream itself is left to expire after expire_secs
+    // in case anything is still said about the game afterwards (eg: a rematch offer)
+    pub async fn trim_game_events(&self, game_id: u32, keep: usize, expire_secs: i64) -> Result<(), redis::RedisError> {
+        let mut con = self.connection.lock().await;
+        let key = format!("game_events:{}", game_id);
+        let _: usize = con.xtrim(&key, StreamMaxlen::Equals(keep)).await?;
+        con.expire(&key, expire_secs).await
+    }
+
     pub async fn get_pubsub(&self) -> PubsubConnection{
         // let redis_url = env::var("REDIS_URL").unwrap();
         let redis_url = "127.0.0.1".to_string(); //because this crate doesnt accept //redis...
         let pubsub = pubsub_connect(redis_url, 6379).await.unwrap();
         pubsub
     }
-}
\ No newline at end of file
+}
+
+// One attempt at update_ratings, None if either rating changed before the transaction ran
+async fn try_update_ratings(con: &mut MultiplexedConnection, white_key: &str, black_key: &str, white_score: f64) -> Result<Option<((Rating, Rating), (Rating, Rating))>, redis::RedisError> {
+    redis::cmd("WATCH").arg(white_key).arg(black_key).query_async::<()>(con).await?;
+
+    let white_stats: HashMap<String, String> = con.hgetall(white_key).await?;
+    let black_stats: HashMap<String, String> = con.hgetall(black_key).await?;
+    let white_before = rating_from_stats(&white_stats);
+    let black_before = rating_from_stats(&black_stats);
+
+    let white_after = white_before.updated(&black_before, white_score);
+    let black_after = black_before.updated(&white_before, 1.0 - white_score);
+
+    let committed: Option<()> = redis::pipe()
+        .atomic()
+        .hset_multiple(white_key, &rating_fields(&white_after)).ignore()
+        .hset_multiple(black_key, &rating_fields(&black_after)).ignore()
+        .query_async(con)
+        .await?;
+
+    Ok(committed.map(|_| ((white_before, white_after), (black_before, black_after))))
+}
+
+fn rating_from_stats(stats: &HashMap<String, String>) -> Rating {
+    let field = |name: &str| stats.get(name).and_then(|value| value.parse::<f64>().ok());
+    match (field("rating"), field("rating_deviation"), field("rating_volatility")) {
+        (Some(rating), Some(deviation), Some(volatility)) => Rating { rating, deviation, volatility },
+        _ => Rating::default(), // players who have never finished a rated game
+    }
+}
+
+fn rating_fields(rating: &Rating) -> [(&'static str, f64); 3] {
+    [
+        ("rating", rating.rating),
+        ("rating_deviation", rating.deviation),
+        ("rating_volatility", rating.volatility),
+    ]
+}
+
+// Reads a game back from its game:{game_id} hash
+fn parse_game(data: &HashMap<String, String>) -> Option<Game> {
+    let time_control: TimeControl = data.get("time_control")
+        .and_then(|time_control_str| serde_json::from_str(time_control_str).ok())
+        .unwrap_or_default();
+    let game = Game {
+        game_id: data.get("game_id")?.parse().ok().unwrap(),
+        player_white: data.get("player_white").unwrap().parse().ok().unwrap(),
+        player_black: data.get("player_black").unwrap().parse().ok().unwrap(),
+        game_created: data.get("game_created").unwrap().parse().ok().unwrap(),
+        game_initiated: data.get("game_initiated").unwrap().parse().ok().unwrap(),
+        game_ended: data.get("game_ended").and_then(|ended| ended.parse().ok()).unwrap_or(0),
+        last_moved: {
+            let last_moved_str = data.get("last_moved").unwrap();
+            let last_moved_tuple: (u32, i64) = serde_json::from_str(last_moved_str).ok().unwrap();
+            last_moved_tuple
+        },
+        board_state: data.get("board_state").unwrap().clone(),
+        previous_move: {
+            let previous_move_str = data.get("previous_move").unwrap();
+            serde_json::from_str(previous_move_str).ok().unwrap()
+        },
+        draw_offer: data.get("draw_offer")
+            .and_then(|draw_offer_str| serde_json::from_str(draw_offer_str).ok())
+            .flatten(),
+        result: data.get("result")
+            .and_then(|result_str| serde_json::from_str(result_str).ok())
+            .flatten(),
+        termination: data.get("termination")
+            .and_then(|termination_str| serde_json::from_str(termination_str).ok())
+            .flatten(),
+        position_history: data.get("position_history")
+            .and_then(|history_str| serde_json::from_str(history_str).ok())
+            .unwrap_or_default(),
+        time_control,
+        rated: data.get("rated").and_then(|rated| rated.parse().ok()).unwrap_or(true),
+        variant: data.get("variant")
+            .and_then(|variant_str| serde_json::from_str(variant_str).ok())
+            .unwrap_or_default(),
+        // games created before clocks were added, or a partly written hash, get a fresh clock
+        clock: data.get("clock")
+            .and_then(|clock_str| serde_json::from_str(clock_str).ok())
+            .unwrap_or_else(|| Clock::new(&time_control)),
+        moves: data.get("moves")
+            .and_then(|moves_str| serde_json::from_str(moves_str).ok())
+            .unwrap_or_default(),
+        rating_change_white: data.get("rating_change_white")
+            .and_then(|change_str| serde_json::from_str(change_str).ok())
+            .flatten(),
+        rating_change_black: data.get("rating_change_black")
+            .and_then(|change_str| serde_json::from_str(change_str).ok())
+            .flatten(),
+        ply: data.get("ply").and_then(|ply| ply.parse().ok()).unwrap_or(0),
+    };
+    Some(game)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOVERS: u32 = 16;
+    const TEST_GAME_ID: u32 = 4_200_000_000;
+    const START_PLY: u32 = 7;
+
+    async fn cleanup(redislayer: &RedisLayer) {
+        let _ = redislayer.del(&format!("game:{}", TEST_GAME_ID)).await;
+        let _ = redislayer.zrem("active_games", &TEST_GAME_ID.to_string()).await;
+    }
+
+    async fn ply(redislayer: &RedisLayer) -> u32 {
+        redislayer.hget(&format!("game:{}", TEST_GAME_ID), "ply").await.unwrap().parse().unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "needs a redis server at REDIS_URL"]
+    async fn concurrent_moves_at_one_ply_apply_exactly_once_and_never_after_the_game_finishes() {
+        let redislayer = RedisLayer::new().await;
+        cleanup(&redislayer).await;
+        redislayer.hset(&format!("game:{}", TEST_GAME_ID), "ply", &START_PLY.to_string()).await.unwrap();
+        redislayer.zadd("active_games", &TEST_GAME_ID.to_string(), 0.0).await.unwrap();
+
+        // every mover has a connection of its own, as separate game servers would
+        let movers: Vec<_> = (0..MOVERS).map(|mover| tokio::spawn(async move {
+            let redislayer = RedisLayer::new().await;
+            let fields = vec![("board_state".to_string(), format!("mover {}", mover))];
+            redislayer.apply_move(TEST_GAME_ID, START_PLY, &fields).await
+        })).collect();
+        let mut applied = 0;
+        for mover in movers {
+            if mover.await.unwrap().unwrap() {
+                applied += 1;
+            }
+        }
+        assert_eq!(applied, 1);
+        assert_eq!(ply(&redislayer).await, START_PLY + 1);
+
+        // finish_game takes the game out of active_games before anything else
+        redislayer.zrem("active_games", &TEST_GAME_ID.to_string()).await.unwrap();
+        let fields = vec![("board_state".to_string(), "after the game".to_string())];
+        assert!(!redislayer.apply_move(TEST_GAME_ID, START_PLY + 1, &fields).await.unwrap());
+        assert_eq!(ply(&redislayer).await, START_PLY + 1);
+        assert_ne!(redislayer.hget(&format!("game:{}", TEST_GAME_ID), "board_state").await.as_deref(), Some("after the game"));
+
+        cleanup(&redislayer).await;
+    }
+
+    fn game_hash(clock: Option<&str>) -> HashMap<String, String> {
+        let mut data: HashMap<String, String> = [
+            ("game_id", "1"),
+            ("player_white", "10"),
+            ("player_black", "20"),
+            ("game_created", "1700000000"),
+            ("game_initiated", "1700000005"),
+            ("last_moved", "[20,1700000005]"),
+            ("board_state", "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"),
+            ("previous_move", "null"),
+            ("time_control", r#"{"initialSecs":180,"incrementSecs":2}"#),
+        ].into_iter().map(|(field, value)| (field.to_string(), value.to_string())).collect();
+        if let Some(clock) = clock {
+            data.insert("clock".to_string(), clock.to_string());
+        }
+        data
+    }
+
+    #[test]
+    fn a_game_without_a_readable_clock_gets_a_fresh_one_for_its_time_control() {
+        let fresh = Clock { white_ms: 180_000, black_ms: 180_000, turn_started_ms: 0 };
+        assert_eq!(parse_game(&game_hash(None)).unwrap().clock, fresh);
+        assert_eq!(parse_game(&game_hash(Some("{\"whiteMs\":"))).unwrap().clock, fresh);
+
+        let stored = r#"{"whiteMs":170000,"blackMs":175000,"turnStartedMs":1700000009000}"#;
+        let clock = parse_game(&game_hash(Some(stored))).unwrap().clock;
+        assert_eq!(clock, Clock { white_ms: 170_000, black_ms: 175_000, turn_started_ms: 1_700_000_009_000 });
+    }
+}
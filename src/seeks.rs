@@ -0,0 +1,284 @@
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Path},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::Utc;
+use futures::StreamExt;
+use http::{Method, Request};
+use log::info;
+use redis_async::resp::FromResp;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+use crate::{authlayer, gameserver::{ColourPreference, GameSettings}, matchmaking::{self, AcceptError, MatchmakingRequest, OfferedGame}, protocol::{ClientMessage, ServerMessage}, redislayer::RedisLayer, utils::cors_response};
+
+// seeks are listed in the order they were posted
+const OPEN_SEEKS: &str = "open_seeks";
+// seek:added:{id} and seek:removed:{id} are published here for the live feed
+const SEEK_UPDATES: &str = "seek_updates";
+const SEEK_EXPIRY_SECS: u64 = 30 * 60;
+
+// A game posted to the lobby for anyone to pick
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Seek {
+    pub seek_id: String,
+    pub user_id: u32,
+    pub rating: f64,
+    pub provisional: bool,
+    pub colour: ColourPreference, // the seeker's side
+    pub settings: GameSettings,
+    pub created: i64,
+}
+
+// Body of POST /seeks, game settings are read the same way as POST /matchmaking
+#[derive(Debug, Default, Deserialize)]
+struct SeekRequest {
+    colour: Option<ColourPreference>,
+    #[serde(flatten)]
+    settings: MatchmakingRequest,
+}
+
+pub async fn seek_options(req: Request<hyper::Body>) -> impl IntoResponse {
+    if req.method() == Method::OPTIONS { //respond to preflight request
+        return cors_response(StatusCode::OK, json!({"message": "Preflight request OK"}));
+    }
+    cors_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Request method is not OPTIONS!"}))
+}
+
+pub async fn create_seek(req: Request<hyper::Body>) -> impl IntoResponse {
+    info!("POST /seeks hit!");
+
+    let user_id = match authlayer::get_jwt_sub(&req).await {
+        Ok(id) => id,
+        Err(e) => return cors_response(e.0, json!({"message": format!("encountered error: {}", e.1)})),
+    };
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return cors_response(StatusCode::BAD_REQUEST, json!({"message": format!("Failed to read request body: {}", e)})),
+    };
+
+    let seek_request: SeekRequest = if body.is_empty() {
+        SeekRequest::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(seek_request) => seek_request,
+            Err(e) => return cors_response(StatusCode::BAD_REQUEST, json!({"message": format!("Invalid seek request: {}", e)})),
+        }
+    };
+
+    let settings = match seek_request.settings.into_settings() {
+        Ok(settings) => settings,
+        Err(message) => return cors_response(StatusCode::BAD_REQUEST, json!({"message": message})),
+    };
+
+    let redislayer = RedisLayer::new().await;
+
+    // one open seek per user, an expired seek_entry means the old seek has gone too
+    if let Some(seek) = current_seek(user_id, &redislayer).await {
+        return cors_response(StatusCode::BAD_REQUEST, json!({"message": "User already has an open seek", "seek": seek}));
+    }
+    if redislayer.hget(&format!("user:{}", user_id), "game_id").await.is_some() {
+        return cors_response(StatusCode::CONFLICT, json!({"message": "User is already playing a game"}));
+    }
+
+    let rating = redislayer.get_rating(user_id).await.unwrap_or_default();
+    let seek = Seek {
+        seek_id: Uuid::new_v4().simple().to_string(),
+        user_id,
+        rating: rating.rating.round(),
+        provisional: rating.is_provisional(),
+        colour: seek_request.colour.unwrap_or_default(),
+        settings,
+        created: Utc::now().timestamp(),
+    };
+
+    if let Err(e) = redislayer.set_ex(&seek_key(&seek.seek_id), &serde_json::to_string(&seek).unwrap(), SEEK_EXPIRY_SECS).await {
+        return cors_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": format!("encountered error saving seek: {}", e)}));
+    }
+    let _ = redislayer.set_ex(&format!("seek_entry:{}", user_id), &seek.seek_id, SEEK_EXPIRY_SECS).await;
+    let _ = redislayer.zadd(OPEN_SEEKS, &seek.seek_id, seek.created as f64).await;
+    let _ = redislayer.publish(SEEK_UPDATES, &format!("seek:added:{}", seek.seek_id)).await;
+
+    info!("user {} posted seek {}", user_id, seek.seek_id);
+
+    cors_response(StatusCode::CREATED, json!({"message": "Seek created", "seek": seek}))
+}
+
+pub async fn list_seeks() -> impl IntoResponse {
+    let redislayer = RedisLayer::new().await;
+    cors_response(StatusCode::OK, json!({"seeks": open_seeks(&redislayer).await}))
+}
+
+pub async fn cancel_seek(Path(seek_id): Path<String>, req: Request<hyper::Body>) -> impl IntoResponse {
+    info!("DELETE /seeks/{} hit!", seek_id);
+
+    let user_id = match authlayer::get_jwt_sub(&req).await {
+        Ok(id) => id,
+        Err(e) => return cors_response(e.0, json!({"message": format!("encountered error: {}", e.1)})),
+    };
+
+    let redislayer = RedisLayer::new().await;
+
+    let seek = match load_seek(&seek_id, &redislayer).await {
+        Some(seek) => seek,
+        None => return cors_response(StatusCode::NOT_FOUND, json!({"message": "Seek not found or expired"})),
+    };
+    if seek.user_id != user_id {
+        return cors_response(StatusCode::FORBIDDEN, json!({"message": "Only the seeker can cancel a seek"}));
+    }
+
+    match claim_seek(&seek, &redislayer).await {
+        Ok(true) => (),
+        Ok(false) => return cors_response(StatusCode::CONFLICT, json!({"message": "Seek has already been accepted"})),
+        Err(e) => return cors_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": format!("encountered error cancelling seek: {}", e)})),
+    }
+    close_seek(&seek, &redislayer).await;
+
+    cors_response(StatusCode::OK, json!({"message": "Seek cancelled"}))
+}
+
+pub async fn accept_seek(Path(seek_id): Path<String>, req: Request<hyper::Body>) -> impl IntoResponse {
+    info!("POST /seeks/{}/accept hit!", seek_id);
+
+    let user_id = match authlayer::get_jwt_sub(&req).await {
+        Ok(id) => id,
+        Err(e) => return cors_response(e.0, json!({"message": format!("encountered error: {}", e.1)})),
+    };
+
+    let redislayer = RedisLayer::new().await;
+
+    let seek = match load_seek(&seek_id, &redislayer).await {
+        Some(seek) => seek,
+        None => return cors_response(StatusCode::NOT_FOUND, json!({"message": "Seek not found or expired"})),
+    };
+    if seek.user_id == user_id {
+        return cors_response(StatusCode::BAD_REQUEST, json!({"message": "Users cannot accept their own seek"}));
+    }
+
+    let claim = claim_seek(&seek, &redislayer);
+    let restore = restore_seek(&seek, &redislayer);
+    let close = close_seek(&seek, &redislayer);
+    let offer = OfferedGame { offered_by: seek.user_id, colour: seek.colour, settings: seek.settings };
+    match matchmaking::accept_offer(offer, user_id, claim, restore, close, &redislayer).await {
+        Ok(game_id) => cors_response(StatusCode::OK, json!({
+            "message": format!("Seek accepted, created game: {}", game_id),
+            "game_id": game_id,
+            "instructions": "Open a websocket request to the server at /ws"
+        })),
+        Err(AcceptError::Taken) => cors_response(StatusCode::CONFLICT, json!({"message": "Seek has already been taken"})),
+        Err(AcceptError::Conflict(message)) => cors_response(StatusCode::CONFLICT, json!({"message": message})),
+        Err(AcceptError::Failed(message)) => cors_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": message})),
+    }
+}
+
+// Live feed of the seek lobby, the current seeks are sent first and then every addition and removal
+pub async fn seek_feed_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
+    ws.on_upgrade(seek_feed)
+}
+
+async fn seek_feed(mut stream: WebSocket) {
+    let redislayer = RedisLayer::new().await;
+    let pubsub = redislayer.get_pubsub().await;
+    let mut seek_updates = match pubsub.subscribe(SEEK_UPDATES).await {
+        Ok(seek_updates) => seek_updates,
+        Err(e) => {
+            info!("Failed to subscribe to seek updates: {}", e);
+            let _ = stream.close().await;
+            return;
+        }
+    };
+
+    let snapshot = ServerMessage::Seeks(open_seeks(&redislayer).await);
+    if stream.send(snapshot.to_ws()).await.is_err() {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    if let Ok(ClientMessage::Ping) = serde_json::from_str(&text) {
+                        let _ = stream.send(ServerMessage::Pong.to_ws()).await;
+                    }
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => (),
+            },
+            Some(update) = seek_updates.next() => {
+                let payload = match update.map(String::from_resp) {
+                    Ok(Ok(payload)) => payload,
+                    _ => continue,
+                };
+                let parts: Vec<&str> = payload.split(':').collect();
+                if parts.len() != 3 || parts[0] != "seek" {
+                    continue;
+                }
+                let message = match parts[1] {
+                    "added" => match load_seek(parts[2], &redislayer).await {
+                        Some(seek) => ServerMessage::SeekAdded(seek),
+                        None => continue, // taken before we could read it
+                    },
+                    "removed" => ServerMessage::SeekRemoved { seek_id: parts[2].to_string() },
+                    _ => continue,
+                };
+                if stream.send(message.to_ws()).await.is_err() {
+                    break;
+                }
+            },
+        }
+    }
+    info!("seek feed closed");
+}
+
+// Removing the seek from open_seeks is what claims it, so only one accept or cancel can succeed
+async fn claim_seek(seek: &Seek, redislayer: &RedisLayer) -> Result<bool, redis::RedisError> {
+    redislayer.zrem(OPEN_SEEKS, &seek.seek_id).await.map(|removed| removed == 1)
+}
+
+// Clears up a claimed seek and takes it off the live feed
+async fn close_seek(seek: &Seek, redislayer: &RedisLayer) {
+    let _ = redislayer.del(&seek_key(&seek.seek_id)).await;
+    let _ = redislayer.del(&format!("seek_entry:{}", seek.user_id)).await;
+    let _ = redislayer.publish(SEEK_UPDATES, &format!("seek:removed:{}", seek.seek_id)).await;
+}
+
+// Reopens a seek claimed by an accept which couldn't go ahead, in its original place in the list
+async fn restore_seek(seek: &Seek, redislayer: &RedisLayer) {
+    if let Err(e) = redislayer.zadd(OPEN_SEEKS, &seek.seek_id, seek.created as f64).await {
+        info!("Failed to restore seek {}: {}", seek.seek_id, e);
+    }
+}
+
+async fn open_seeks(redislayer: &RedisLayer) -> Vec<Seek> {
+    let mut seeks = Vec::new();
+    for seek_id in redislayer.zrange(OPEN_SEEKS, 0, -1).await.unwrap_or_default() {
+        match load_seek(&seek_id, redislayer).await {
+            Some(seek) => seeks.push(seek),
+            None => {
+                // expired, let anyone watching the feed know it has gone
+                if let Ok(1) = redislayer.zrem(OPEN_SEEKS, &seek_id).await {
+                    let _ = redislayer.publish(SEEK_UPDATES, &format!("seek:removed:{}", seek_id)).await;
+                }
+            },
+        }
+    }
+    seeks
+}
+
+async fn current_seek(user_id: u32, redislayer: &RedisLayer) -> Option<Seek> {
+    let seek_id = redislayer.get(&format!("seek_entry:{}", user_id)).await.ok()??;
+    load_seek(&seek_id, redislayer).await
+}
+
+async fn load_seek(seek_id: &str, redislayer: &RedisLayer) -> Option<Seek> {
+    let seek = redislayer.get(&seek_key(seek_id)).await.ok()??;
+    serde_json::from_str(&seek).ok()
+}
+
+fn seek_key(seek_id: &str) -> String {
+    format!("seek:{}", seek_id)
+}
@@ -1,7 +1,23 @@
+use axum::http::StatusCode;
+use http::{header, Response};
+use hyper::Body;
+
 pub fn decode_user_id() {
     // takes in the jwt, and returns user id as an int
 }
 
 pub async fn user_id_to_game_id() {
     //takes user id as an int, and checks redis/db (not sure yet) to get the game id if any linked to that user
-}
\ No newline at end of file
+}
+
+pub fn cors_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    let json_body = body.to_string();
+    Response::builder()
+        .status(status)
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*") // Allow requests from any origin
+        .header(header::ACCESS_CONTROL_ALLOW_HEADERS, "*")
+        .header(header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, DELETE, OPTIONS")
+        .header(header::CONTENT_TYPE, "application/json") // Set Content-Type to JSON
+        .body(Body::from(json_body))
+        .unwrap() // Or handle error more gracefully
+}
@@ -5,32 +5,42 @@ use axum::{
 };
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use tokio::{sync::Mutex, task};
-use crate::{authlayer, gameserver::{self, GameServer, Game}, redislayer::{self, RedisLayer}, utils::decode_user_id};
+use tokio::{sync::{watch, Mutex}, task};
+use crate::{authlayer, challenges, events::GameEvent, gameserver::{self, GameServer, Game, GameResult, Termination}, matchmaking::{self, JoinError, LeaveOutcome}, protocol::{self, ClientMessage, ServerMessage}, redislayer::{self, RedisLayer}, utils::decode_user_id};
 use crate::utils::user_id_to_game_id;
 use futures::{stream::{SplitSink, SplitStream}, SinkExt, StreamExt};
 use log::info;
 use redis::PubSub;
+use redis_async::resp::FromResp;
 // extern crate pleco;
 use pleco;
-use pleco::{core::piece_move::{MoveFlag, PreMoveInfo}, BitMove, Board, PieceType, SQ};
+use pleco::{core::piece_move::{MoveFlag, PreMoveInfo}, BitMove, Board, PieceType, Player, SQ};
 use dotenv::dotenv;
+use uuid::Uuid;
 use std::time::Duration;
 use std::thread::sleep;
 
+// how often players waiting in the lobby are sent their place in the queue
+const LOBBY_STATUS_INTERVAL_SECS: u64 = 2;
+// how long a player who drops out of a game in progress has to reconnect before forfeiting
+const RECONNECT_GRACE_SECS: u64 = 60;
+// how long a matched player waits for their opponent to join before the game is aborted
+const READY_UP_TIMEOUT_SECS: u64 = 30;
+const READY_UP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 pub async fn websocket_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
     ws.on_upgrade(handle_socket)
 }
 
 async fn handle_socket(mut stream: WebSocket) { //also takes the token here
 
-    let token: String = match listen_for_token(&mut stream).await {
-        Some(token) => token,
-        None => {
+    let (token, protocol_version, last_event_seq) = match listen_for_token(&mut stream).await {
+        Ok(authenticated) => authenticated,
+        Err(message) => {
             //TODO: need to properly handle closing the stream, if a game is open, the other player should be informed and the game closed.
             // or we have some sort of re-connection within a time window
             //note to self: when a game is created, players have N mins to join before the game expires
-            let _ = stream.send(Message::Text(format!("Authentication failed"))).await;
+            let _ = stream.send(ServerMessage::Error { message }.to_ws()).await;
             let _ = stream.close().await;
             return;
         }
@@ -40,33 +50,41 @@ async fn handle_socket(mut stream: WebSocket) { //also takes the token here
         Ok(id) => id,
         Err(e) => {
             info!("Failed to resolve userId from token: {}", e.1);
+            let _ = stream.send(ServerMessage::Error { message: "Authentication failed".to_string() }.to_ws()).await;
+            let _ = stream.close().await;
             return;
         }
     };
-    info!("Authenticated user: {}", user_id);
+    info!("Authenticated user: {} on protocol version {}", user_id, protocol_version);
+
+    if stream.send(ServerMessage::Welcome { protocol_version, user_id }.to_ws()).await.is_err() {
+        return;
+    }
 
     //establish redis con, which is shared between threads later on
     let redis_layer = redislayer::RedisLayer::new().await;
 
     let game_id = match redis_layer.hget(&format!("user:{}", user_id), "game_id").await {  //check if there is a game in redis for that user id
-        Some(game_id) => game_id,
-        None => {
-            let _ = stream.send(Message::Text(format!("User has no associated game"))).await;
-            let _ = stream.close().await;
-            return;
+        Some(game_id) => match game_id.parse::<u32>() {
+            Ok(game_id) => game_id,
+            Err(e) => {
+                info!("Error parsing game_id: {}", e);
+                return;
+            }
+        },
+        // no game yet, wait in the lobby until matchmaking finds one
+        None => match lobby(&mut stream, user_id, &redis_layer).await {
+            Some(game_id) => game_id,
+            None => {
+                let _ = stream.close().await;
+                return;
+            }
         }
     }; // TODO: clean up old user id -> game id mappings on close
-    let game_id = match game_id.parse::<u32>() {
-        Ok(game_id) => game_id,
-        Err(e) => {
-            info!("Error parsing game_id: {}", e);
-            return;
-        }
-    };
     info!("game id: {}", game_id);
 
-    let game: Game = match redis_layer.get_game(game_id).await {
-        Some(game) => game,
+    let (current_seq, game) = match redis_layer.get_game_snapshot(game_id).await {
+        Some(snapshot) => snapshot,
         None => {
             info!("Failed to get game");
             return;
@@ -75,8 +93,27 @@ async fn handle_socket(mut stream: WebSocket) { //also takes the token here
 
     info!("Found game id: {} for user: {}", game_id, user_id);
 
-    if let Err(e) = ready_up(game, user_id, &redis_layer).await {
+    // a game which has already started is being rejoined after a dropped connection, so there's no need to wait for the opponent
+    let resuming = game.game_initiated != 0 && !game.is_over();
+
+    // a resuming client is sent the full state, so it only needs events from then on unless it asks to replay from further back
+    let after_seq = if resuming {last_event_seq.unwrap_or(current_seq).min(current_seq)} else {0};
+
+    // the latest connection owns the player's seat, so a stale socket dropping later can't forfeit the game
+    let connection_id = Uuid::new_v4().to_string();
+    let _ = redis_layer.hset(&format!("connections:{}", game_id), &user_id.to_string(), &connection_id).await;
+
+    if resuming {
+        info!("user {} reconnected to game {}", user_id, game_id);
+        let _ = redis_layer.publish_game_event(game_id, GameEvent::Reconnected { user_id, clock: Some(game.clock) }).await;
+        if let Err(e) = stream.send(gameserver::format_resume(user_id, game)).await {
+            info!("Failed to resend game state to user {}: {}", user_id, e);
+            return;
+        }
+    } else if let Err(e) = ready_up(game, user_id, &redis_layer).await {
         info!("Encountered Error waiting for game {} to start for user: {}: {}", game_id, user_id, e);
+        let _ = stream.send(ServerMessage::GameAborted { game_id }.to_ws()).await;
+        let _ = stream.close().await;
         return;
     }
 
@@ -86,29 +123,171 @@ async fn handle_socket(mut stream: WebSocket) { //also takes the token here
 
     info!("Spinning up send/receive for user: {}", user_id);
 
+    // the receiver follows the sender onto a new game when a rematch is agreed
+    let (game_switch, game_watch) = watch::channel(game_id);
+
     task::spawn({
         let sender = sender.clone();
+        let connection_id = connection_id.clone();
         async move {
-            message_receiver(receiver, sender, user_id, game_id).await;
+            message_receiver(receiver, sender, user_id, game_watch, connection_id).await;
         }
     });
 
     task::spawn({
         let sender = sender.clone();
         async move {
-            gameserver::message_sender(sender, user_id, game_id).await;
+            let mut game_id = game_id;
+            let mut after_seq = after_seq;
+            while let Some(rematch_id) = gameserver::message_sender(sender.clone(), user_id, game_id, after_seq, &connection_id).await {
+                let rematch = match redis_layer.get_game(rematch_id).await {
+                    Some(rematch) => rematch,
+                    None => {
+                        info!("Failed to get rematch game {}", rematch_id);
+                        break;
+                    }
+                };
+                if let Err(e) = ready_up(rematch, user_id, &redis_layer).await {
+                    info!("Encountered Error waiting for rematch {} to start for user: {}: {}", rematch_id, user_id, e);
+                    let mut sender = sender.lock().await;
+                    let _ = sender.send(ServerMessage::GameAborted { game_id: rematch_id }.to_ws()).await;
+                    let _ = sender.close().await;
+                    break;
+                }
+                let _ = redis_layer.hset(&format!("connections:{}", rematch_id), &user_id.to_string(), &connection_id).await;
+                let _ = game_switch.send(rematch_id);
+                game_id = rematch_id;
+                after_seq = 0;
+            }
         }
     });
 }
 
+// Pre-game phase for users without a game, they can join or leave matchmaking over the socket and are
+// sent their queue status until create_game announces a match on lobby:{user_id}
+async fn lobby(stream: &mut WebSocket, user_id: u32, redislayer: &RedisLayer) -> Option<u32> {
+    let pubsub = redislayer.get_pubsub().await;
+    let mut lobby_updates = pubsub.subscribe(&format!("lobby:{}", user_id)).await.ok()?;
+
+    // the match may have been made before the subscription was in place
+    if let Some(game_id) = redislayer.hget(&format!("user:{}", user_id), "game_id").await.and_then(|id| id.parse::<u32>().ok()) {
+        let _ = stream.send(ServerMessage::MatchFound { game_id }.to_ws()).await;
+        return Some(game_id);
+    }
+
+    let _ = stream.send(ServerMessage::LobbyJoined(matchmaking::queue_status(user_id, redislayer).await).to_ws()).await;
+    let mut status_interval = tokio::time::interval(Duration::from_secs(LOBBY_STATUS_INTERVAL_SECS));
+
+    loop {
+        tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(Message::Text(text))) => handle_lobby_message(stream, &text, user_id, redislayer).await,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    info!("user {} left the lobby", user_id);
+                    // nobody is left to play a game for this user, so stop waiting for one
+                    if let Ok(LeaveOutcome::Matched(Some(game_id))) = matchmaking::leave_matchmaking(user_id, redislayer).await {
+                        // matched just as they left, don't leave the opponent waiting for them
+                        if let Some(game) = redislayer.get_game(game_id).await {
+                            gameserver::abort_game(redislayer, &game).await;
+                        }
+                    }
+                    return None;
+                },
+                _ => (),
+            },
+            Some(update) = lobby_updates.next() => {
+                let payload = match update.map(String::from_resp) {
+                    Ok(Ok(payload)) => payload,
+                    _ => continue,
+                };
+                let parts: Vec<&str> = payload.split(':').collect();
+                if parts.len() == 3 && parts[0] == "match" && parts[1] == "found" {
+                    if let Ok(game_id) = parts[2].parse::<u32>() {
+                        info!("user {} matched into game {} from the lobby", user_id, game_id);
+                        let _ = stream.send(ServerMessage::MatchFound { game_id }.to_ws()).await;
+                        return Some(game_id);
+                    }
+                }
+                if parts.len() == 3 && parts[0] == "challenge" {
+                    let challenge_id = parts[2].to_string();
+                    let message = match parts[1] {
+                        "new" => ServerMessage::ChallengeNew {
+                            challenge: challenges::load_challenge(&challenge_id, redislayer).await,
+                            challenge_id,
+                        },
+                        "accepted" => ServerMessage::ChallengeAccepted { challenge_id },
+                        "declined" => ServerMessage::ChallengeDeclined { challenge_id },
+                        "cancelled" => ServerMessage::ChallengeCancelled { challenge_id },
+                        _ => continue,
+                    };
+                    let _ = stream.send(message.to_ws()).await;
+                }
+            },
+            _ = status_interval.tick() => {
+                if let Some(status) = matchmaking::queue_status(user_id, redislayer).await {
+                    let _ = stream.send(ServerMessage::QueueStatus(status).to_ws()).await;
+                }
+            },
+        }
+    }
+}
+
+async fn handle_lobby_message(stream: &mut WebSocket, text: &str, user_id: u32, redislayer: &RedisLayer) {
+    let message: ClientMessage = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(e) => {
+            info!("Failed to parse lobby message: {}", e);
+            let _ = stream.send(ServerMessage::Error { message: format!("Invalid message: {}", e) }.to_ws()).await;
+            return;
+        }
+    };
+
+    let reply = match message {
+        ClientMessage::Ping => ServerMessage::Pong,
+        ClientMessage::MatchmakingJoin(request) => {
+            let settings = match request.into_settings() {
+                Ok(settings) => settings,
+                Err(message) => {
+                    let _ = stream.send(ServerMessage::MatchmakingError { message }.to_ws()).await;
+                    return;
+                }
+            };
+            match matchmaking::join_matchmaking(user_id, &settings, redislayer).await {
+                Ok(_) => match matchmaking::queue_status(user_id, redislayer).await {
+                    Some(status) => ServerMessage::QueueStatus(status),
+                    None => return, // matched straight away, match_found follows
+                },
+                Err(JoinError::AlreadyQueued) => ServerMessage::MatchmakingError { message: "User already in matchmaking pool".to_string() },
+                Err(JoinError::Redis(e)) => {
+                    info!("Error adding user {} to matchmaking from the lobby: {}", user_id, e);
+                    ServerMessage::MatchmakingError { message: "Failed to add to matchmaking pool".to_string() }
+                },
+            }
+        },
+        ClientMessage::MatchmakingLeave => {
+            let removed = matches!(matchmaking::leave_matchmaking(user_id, redislayer).await, Ok(LeaveOutcome::Removed));
+            ServerMessage::MatchmakingLeft { removed }
+        },
+        _ => {
+            info!("Ignoring message from user {} which is not valid in the lobby: {}", user_id, text);
+            return;
+        },
+    };
+    let _ = stream.send(reply.to_ws()).await;
+}
+
+// Waits for the opponent to join the game and starts it, if they haven't joined by READY_UP_TIMEOUT_SECS the game is aborted
 async fn ready_up(game: Game, user_id: u32, redislayer: &redislayer::RedisLayer) -> Result<(), String> {
     let opponent_id = if game.player_white == user_id {game.player_black} else {game.player_white};
+    let readiness_key = format!("game_readiness:{}", game.game_id);
 
-    let hset_result = redislayer.hset(&format!("game_readiness:{}", game.game_id), &user_id.to_string(), "ready").await;
+    let hset_result = redislayer.hset(&readiness_key, &user_id.to_string(), "ready").await;
     info!("user {} is ready... {:?}", user_id, hset_result);
 
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(READY_UP_TIMEOUT_SECS);
+
     loop {
-        let result = redislayer.hget(&format!("game_readiness:{}", game.game_id), &opponent_id.to_string()).await;
+        let result = redislayer.hget(&readiness_key, &opponent_id.to_string()).await;
         if result.is_some() {
             info!("opponent ready!");
             //initiate game
@@ -119,62 +298,79 @@ async fn ready_up(game: Game, user_id: u32, redislayer: &redislayer::RedisLayer)
                 "game_initiated",
                 &timestamp
             ).await.expect("Failed to set game as initiated");
-            break;
+
+            //start white's clock, unless the opponent already has
+            if let Some(mut game) = redislayer.get_game(game.game_id).await {
+                if !game.clock.is_running() {
+                    game.clock.turn_started_ms = Utc::now().timestamp_millis();
+                    let _ = redislayer.hset(&format!("game:{}", game.game_id), "clock", &serde_json::to_string(&game.clock).unwrap()).await;
+                    gameserver::track_clock_deadline(redislayer, game.game_id, &game.clock, Player::White).await;
+                }
+            }
+            return Ok(());
         }
-    }
-    Ok(())
-}
 
-async fn listen_for_token(stream: &mut WebSocket) -> Option<String> {
-    if let Some(Ok(Message::Text(text))) = stream.next().await {
-        let data: serde_json::Value = serde_json::from_str(&text).ok()?;
-        let token_value = data.get("token")?.to_string();
-        let token_value = token_value.trim_matches('"').to_string();
+        // the opponent may have given up on the game, eg: by closing the lobby
+        match redislayer.get_game(game.game_id).await {
+            Some(current) if current.is_over() => return Err(format!("game {} was aborted", game.game_id)),
+            None => return Err(format!("game {} no longer exists", game.game_id)),
+            _ => (),
+        }
 
-        if authlayer::validate_token(&token_value.as_str()).await.is_ok() {
-            info!("Authenticated via WebSocket");
-            return Some(token_value);
+        if tokio::time::Instant::now() >= deadline {
+            gameserver::abort_game(redislayer, &game).await;
+            return Err(format!("user {} did not join game {} in time", opponent_id, game.game_id));
         }
+        tokio::time::sleep(READY_UP_POLL_INTERVAL).await;
     }
+}
 
-    None
+// The first message must be authenticate, returns the token, the protocol version both sides will speak and the
+// last game event the client saw
+async fn listen_for_token(stream: &mut WebSocket) -> Result<(String, u32, Option<u64>), String> {
+    let text = match stream.next().await {
+        Some(Ok(Message::Text(text))) => text,
+        _ => return Err("Expected an authenticate message".to_string()),
+    };
+
+    let (token, client_version, last_event_seq) = match serde_json::from_str(&text) {
+        Ok(ClientMessage::Authenticate { token, protocol_version, last_event_seq }) => (token, protocol_version, last_event_seq),
+        Ok(_) => return Err("The first message must be authenticate".to_string()),
+        Err(e) => return Err(format!("Invalid authenticate message: {}", e)),
+    };
+
+    let protocol_version = protocol::negotiate_version(client_version).ok_or(format!(
+        "Unsupported protocol version {}, the server speaks versions {} to {}",
+        client_version, protocol::MIN_PROTOCOL_VERSION, protocol::PROTOCOL_VERSION
+    ))?;
+
+    if authlayer::validate_token(token.as_str()).await.is_err() {
+        return Err("Authentication failed".to_string());
+    }
+    info!("Authenticated via WebSocket");
+    Ok((token, protocol_version, last_event_seq))
 }
 
-async fn message_receiver(mut receiver: SplitStream<WebSocket>, sender: Arc<Mutex<SplitSink<WebSocket, Message>>>, user_id: u32, game_id: u32) {
-    let gameserver = GameServer::new(game_id, user_id).await;
+async fn message_receiver(mut receiver: SplitStream<WebSocket>, sender: Arc<Mutex<SplitSink<WebSocket, Message>>>, user_id: u32, mut game_watch: watch::Receiver<u32>, connection_id: String) {
+    let mut game_id = *game_watch.borrow_and_update();
+    let mut gameserver = GameServer::new(game_id, user_id, sender.clone()).await;
 
     while let Some(message_result) = receiver.next().await {
+        if game_watch.has_changed().unwrap_or(false) {
+            game_id = *game_watch.borrow_and_update();
+            info!("user {} moved on to rematch game {}", user_id, game_id);
+            gameserver = GameServer::new(game_id, user_id, sender.clone()).await;
+        }
         match message_result {
             Ok(message) => {
                 match message {
-                    Message::Text(text) => {
-                        if text == "0" { //handle PING, (sent as a "0")
-                            let mut sender = sender.lock().await;
-                            let _send_result = sender.send(Message::Text("PONG".to_string())).await; //TODO: fail if this fails
-                        continue;
-                        }
-                        gameserver.handle_received_message(text).await;
-                    },
+                    Message::Text(text) => gameserver.handle_received_message(text).await,
                     Message::Close(reason) => {
                         info!("Close message received: {:?}", reason);
                         let mut sender = sender.lock().await;
                         let _ = sender.send(Message::Close(reason)).await;
-                        let redislayer = RedisLayer::new().await;
-                        let _ = redislayer.publish(&format!("game_updates:{}", game_id), "game:close").await;
+                        drop(sender);
                         info!("Connection closed by client");
-                        let redis_layer = RedisLayer::new().await;
-                        let game = redis_layer.get_game(game_id).await.expect("failed to get game");
-                
-                        let rem_result = redis_layer.zrem("active_games", &game_id.to_string()).await;
-                        match rem_result {
-                            Ok(_) => info!("removed game from active game pool!"),
-                            Err(e) => info!("Failed to remove game from active games!, {}", e)
-                        }
-                
-                        let _ = redis_layer.del(&format!("user:{}",user_id)).await;
-                        let _ = redis_layer.del(&format!("user:{}",if game.player_black == user_id {game.player_white} else {game.player_black})).await;
-                
-                        let _ = redis_layer.publish(&format!("game_updates:{}", game.game_id), &format!("player:surrender:{}", user_id)).await;
                         break;
                     },
                     _ => {
@@ -190,6 +386,55 @@ async fn message_receiver(mut receiver: SplitStream<WebSocket>, sender: Arc<Mute
         }
     }
     info!("Exited message receiving loop for user: {}", user_id);
+
+    // a rematch may have been agreed since the last message
+    let game_id = *game_watch.borrow();
+    handle_disconnect(user_id, game_id, &connection_id).await;
+}
+
+// A player who drops out of a game in progress has RECONNECT_GRACE_SECS to reconnect before forfeiting it,
+// their clock keeps running in the meantime. A game where either player has yet to move is aborted instead
+async fn handle_disconnect(user_id: u32, game_id: u32, connection_id: &str) {
+    let redis_layer = RedisLayer::new().await;
+    let connections_key = format!("connections:{}", game_id);
+
+    let game = match redis_layer.get_game(game_id).await {
+        Some(game) => game,
+        None => return,
+    };
+    // nothing to forfeit, or the player has already reconnected on another socket
+    if game.is_over() || redis_layer.hget(&connections_key, &user_id.to_string()).await.as_deref() != Some(connection_id) {
+        return;
+    }
+
+    info!("user {} disconnected from game {}, waiting {}s for them to reconnect", user_id, game_id, RECONNECT_GRACE_SECS);
+    let _ = redis_layer.publish_game_event(game_id, GameEvent::Disconnected { user_id, clock: Some(game.clock) }).await;
+
+    tokio::time::sleep(Duration::from_secs(RECONNECT_GRACE_SECS)).await;
+
+    if redis_layer.hget(&connections_key, &user_id.to_string()).await.as_deref() != Some(connection_id) {
+        return;
+    }
+    let game = match redis_layer.get_game(game_id).await {
+        Some(game) if !game.is_over() => game,
+        _ => return,
+    };
+
+    // leaving before both players have moved calls the game off rather than losing it
+    if game.ply < 2 {
+        info!("user {} did not reconnect to game {}, aborting", user_id, game_id);
+        gameserver::abort_game(&redis_layer, &game).await;
+        return;
+    }
+
+    info!("user {} did not reconnect to game {}, forfeiting", user_id, game_id);
+    let result = if game.player_white == user_id {GameResult::BlackWins} else {GameResult::WhiteWins};
+    if !gameserver::claim_game(&redis_layer, game_id).await {
+        return;
+    }
+    let surrendered = GameEvent::Surrendered { user_id, result: Some(result), termination: Some(Termination::Abandonment) };
+    let _ = redis_layer.publish_game_event(game_id, surrendered).await;
+    gameserver::settle_game(&redis_layer, &game, result, Termination::Abandonment).await;
 }
 
 